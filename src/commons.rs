@@ -16,4 +16,11 @@ impl<A> Locked<A> {
     pub fn lock(&self) -> spin::MutexGuard<A> {
         self.inner.lock()
     }
+
+    /// Tries to lock without spinning, returning `None` if the lock is
+    /// already held. Useful in interruption handlers, where waiting for
+    /// the interrupted code to release the lock would deadlock.
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<A>> {
+        self.inner.try_lock()
+    }
 }
@@ -0,0 +1,194 @@
+//! Minimal ACPI support, used to discover the interrupt controllers.
+//! The RSDP is searched in the BIOS areas (EBDA and 0xE0000-0xFFFFF), then
+//! the RSDT/XSDT is used to find the MADT ("APIC" table), which lists the
+//! Local APIC address, the I/O APICs and how the ISA IRQs are wired to them.
+//!
+//! Tables are read through the physical memory mapping, as they live in RAM
+//! regions reported by the memory map. Only the first I/O APIC is kept, which
+//! is enough for single-chipset machines such as QEMU.
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Size of the header common to every ACPI table
+const SDT_HEADER_SIZE: u64 = 36;
+/// Number of legacy ISA IRQs
+pub const ISA_IRQS: usize = 16;
+
+/// MADT entry types
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+/// MADT flag telling the machine also has the legacy 8259 PICs
+const MADT_PCAT_COMPAT: u32 = 1;
+
+/// An I/O APIC described by the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt handled by this I/O APIC
+    pub gsi_base: u32,
+}
+
+/// Wiring of an ISA IRQ to a global system interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    /// MPS INTI flags: polarity (bits 0-1) and trigger mode (bits 2-3)
+    pub flags: u16,
+}
+
+/// The interrupt controllers information of the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    pub io_apic: Option<IoApicEntry>,
+    /// Whether the legacy PICs are present (and must be masked)
+    pub has_legacy_pics: bool,
+    overrides: [Option<IsaRoute>; ISA_IRQS],
+}
+
+impl Madt {
+    /// Returns how the given ISA IRQ is wired: identity mapped with the
+    /// ISA defaults (edge triggered, active high) unless overridden.
+    pub fn isa_route(&self, irq: u8) -> IsaRoute {
+        self.overrides
+            .get(irq as usize)
+            .copied()
+            .flatten()
+            .unwrap_or(IsaRoute {
+                gsi: irq as u32,
+                flags: 0,
+            })
+    }
+}
+
+/// Looks for the MADT using the physical memory mapped at the given offset
+pub fn find_madt(physical_memory_offset: VirtAddr) -> Option<Madt> {
+    let tables = AcpiTables {
+        offset: physical_memory_offset,
+    };
+    let rsdp = tables.find_rsdp()?;
+    let madt = tables.find_table(rsdp, b"APIC")?;
+    Some(tables.parse_madt(madt))
+}
+
+struct AcpiTables {
+    offset: VirtAddr,
+}
+
+impl AcpiTables {
+    fn read<T: Copy>(&self, addr: PhysAddr) -> T {
+        unsafe { core::ptr::read_unaligned((self.offset + addr.as_u64()).as_ptr::<T>()) }
+    }
+
+    fn checksum_ok(&self, addr: PhysAddr, len: u64) -> bool {
+        (0..len).fold(0u8, |sum, index| {
+            sum.wrapping_add(self.read::<u8>(addr + index))
+        }) == 0
+    }
+
+    fn find_rsdp(&self) -> Option<PhysAddr> {
+        // the EBDA segment is stored in the BIOS data area
+        let ebda = (self.read::<u16>(PhysAddr::new(0x40e)) as u64) << 4;
+        let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+        areas
+            .iter()
+            .filter(|(start, _)| *start != 0)
+            .flat_map(|&(start, end)| (start..end).step_by(16))
+            .map(PhysAddr::new)
+            .find(|&addr| self.read::<[u8; 8]>(addr) == *b"RSD PTR " && self.checksum_ok(addr, 20))
+    }
+
+    /// Finds a table by signature using the XSDT (ACPI 2.0+) or the RSDT
+    fn find_table(&self, rsdp: PhysAddr, signature: &[u8; 4]) -> Option<PhysAddr> {
+        let revision = self.read::<u8>(rsdp + 15u64);
+        let xsdt = self.read::<u64>(rsdp + 24u64);
+        let (root, entry_size) = if revision >= 2 && xsdt != 0 {
+            (PhysAddr::new(xsdt), 8)
+        } else {
+            (PhysAddr::new(self.read::<u32>(rsdp + 16u64) as u64), 4)
+        };
+        if !self.is_valid_table(root) {
+            return None;
+        }
+
+        let length = self.read::<u32>(root + 4u64) as u64;
+        (SDT_HEADER_SIZE..length)
+            .step_by(entry_size)
+            .map(|offset| {
+                let entry = root + offset;
+                if entry_size == 8 {
+                    PhysAddr::new(self.read::<u64>(entry))
+                } else {
+                    PhysAddr::new(self.read::<u32>(entry) as u64)
+                }
+            })
+            .find(|&table| self.read::<[u8; 4]>(table) == *signature && self.is_valid_table(table))
+    }
+
+    fn is_valid_table(&self, table: PhysAddr) -> bool {
+        let length = self.read::<u32>(table + 4u64) as u64;
+        length >= SDT_HEADER_SIZE && self.checksum_ok(table, length)
+    }
+
+    fn parse_madt(&self, table: PhysAddr) -> Madt {
+        let mut madt = Madt {
+            local_apic: PhysAddr::new(self.read::<u32>(table + SDT_HEADER_SIZE) as u64),
+            io_apic: None,
+            has_legacy_pics: self.read::<u32>(table + SDT_HEADER_SIZE + 4u64) & MADT_PCAT_COMPAT
+                != 0,
+            overrides: [None; ISA_IRQS],
+        };
+
+        let end = table + self.read::<u32>(table + 4u64) as u64;
+        let mut entry = table + SDT_HEADER_SIZE + 8u64;
+        while entry + 2u64 <= end {
+            let entry_type = self.read::<u8>(entry);
+            let length = self.read::<u8>(entry + 1u64) as u64;
+            if length < 2 {
+                break;
+            }
+            match entry_type {
+                MADT_IO_APIC if madt.io_apic.is_none() => {
+                    madt.io_apic = Some(IoApicEntry {
+                        id: self.read::<u8>(entry + 2u64),
+                        address: PhysAddr::new(self.read::<u32>(entry + 4u64) as u64),
+                        gsi_base: self.read::<u32>(entry + 8u64),
+                    });
+                }
+                MADT_INTERRUPT_OVERRIDE => {
+                    let irq = self.read::<u8>(entry + 3u64) as usize;
+                    if irq < ISA_IRQS {
+                        madt.overrides[irq] = Some(IsaRoute {
+                            gsi: self.read::<u32>(entry + 4u64),
+                            flags: self.read::<u16>(entry + 8u64),
+                        });
+                    }
+                }
+                MADT_LOCAL_APIC_OVERRIDE => {
+                    madt.local_apic = PhysAddr::new(self.read::<u64>(entry + 4u64));
+                }
+                _ => {}
+            }
+            entry += length;
+        }
+        madt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hal::arch::x86_64::memory::memory;
+
+    use super::find_madt;
+
+    #[test_case]
+    fn test_madt_describes_interrupt_controllers() {
+        let offset = memory().lock().physical_memory_offset();
+        let madt = find_madt(offset).expect("MADT not found");
+        assert_eq!(madt.local_apic.as_u64(), 0xfee0_0000);
+        assert!(madt.io_apic.is_some());
+        // the keyboard isn't remapped on PCs
+        assert_eq!(madt.isa_route(1).gsi, 1);
+    }
+}
@@ -0,0 +1,549 @@
+//! Process address spaces.
+//! Each `AddressSpace` has its own level 4 page table. The entries outside the
+//! user space window are copied from the kernel level 4 table when the address
+//! space is created, so the kernel mappings are shared by every address space,
+//! while the user space window is private.
+//!
+//! The kernel doesn't live in the upper half. The level 4 entries are used as:
+//! - 0..8: kernel image, boot info and physical memory mapping, placed there
+//!   by the boot loader
+//! - 8..128: user space window (`USER_SPACE_START..USER_SPACE_END`)
+//! - 136: kernel window (`KERNEL_WINDOW_START..KERNEL_WINDOW_END`), holding
+//!   the heap, the kernel stacks, MMIO and reserved areas
+//!
+//! As only the level 4 entries are copied, a kernel mapping is only shared if
+//! its entry exists when the address space is created. The boot loader entries
+//! are there from the start and `Memory` creates the kernel window entry at
+//! boot, so every runtime kernel mapping goes to the kernel window.
+//!
+//! Besides eagerly mapped memory (`map`), ranges can be reserved (`reserve`)
+//! to be mapped on demand by the page fault handler.
+//!
+//! Address spaces can be cloned cheaply with `clone_cow`: both copies share
+//! the frames, mapped read-only and marked with `COW_FLAG`. The first write
+//! to such a page faults, and the handler gives the writer its own copy
+//! (or just restores write access if no one else references the frame).
+use core::ops::Range;
+use core::ptr::NonNull;
+
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{MapToError, TranslateError};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, MapperAllSizes, OffsetPageTable, Page, PageTable,
+    PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::memory::{page_range, Memory};
+use super::vma::{Vma, VmaError, VmaKind, VmaList};
+
+/// First address of the user space window
+pub const USER_SPACE_START: u64 = 0x0000_0400_0000_0000;
+/// End (exclusive) of the user space window
+pub const USER_SPACE_END: u64 = 0x0000_4000_0000_0000;
+
+/// Level 4 entries covering the user space window
+const USER_L4_ENTRIES: Range<usize> = 8..128;
+
+/// Marks writable pages shared copy-on-write (bit available to the OS)
+pub const COW_FLAG: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Flags of the intermediate tables created for user mappings
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::PRESENT.bits()
+        | PageTableFlags::WRITABLE.bits()
+        | PageTableFlags::USER_ACCESSIBLE.bits(),
+);
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    /// There's no free frame left
+    FrameAllocationFailed,
+    /// The range isn't inside the user space window
+    OutsideUserSpace,
+    /// The kernel has mappings inside the user space window
+    KernelMappingInUserSpace(usize),
+    /// The page table update failed
+    MappingFailed(MapToError<Size4KiB>),
+    /// The area can't be reserved
+    ReserveFailed(VmaError),
+}
+
+impl From<MapToError<Size4KiB>> for AddressSpaceError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        AddressSpaceError::MappingFailed(err)
+    }
+}
+
+impl From<VmaError> for AddressSpaceError {
+    fn from(err: VmaError) -> Self {
+        AddressSpaceError::ReserveFailed(err)
+    }
+}
+
+/// An isolated address space, sharing the kernel mappings, until `destroy`
+/// releases its tables and user frames.
+pub struct AddressSpace {
+    l4_frame: PhysFrame,
+    vmas: VmaList,
+}
+
+impl AddressSpace {
+    /// Creates a new address space with an empty user space window.
+    pub fn new(memory: &mut Memory) -> Result<Self, AddressSpaceError> {
+        let l4_frame = memory
+            .frame_allocator()
+            .allocate_frame()
+            .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+        memory.zero_frame(l4_frame);
+
+        let (table, kernel_table) = unsafe {
+            (
+                memory.page_table(l4_frame),
+                memory.page_table(memory.kernel_l4_frame()),
+            )
+        };
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if entry.is_unused() {
+                continue;
+            }
+            if USER_L4_ENTRIES.contains(&index) {
+                unsafe { memory.frame_allocator().deallocate_frame(l4_frame) };
+                return Err(AddressSpaceError::KernelMappingInUserSpace(index));
+            }
+            table[index] = entry.clone();
+        }
+
+        Ok(Self {
+            l4_frame,
+            vmas: VmaList::new(),
+        })
+    }
+
+    /// Frame holding the level 4 table of this address space
+    pub fn l4_frame(&self) -> PhysFrame {
+        self.l4_frame
+    }
+
+    /// Whether this is the address space currently loaded in the CPU
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.l4_frame
+    }
+
+    /// Allocates zeroed frames and maps them to the given range of the user
+    /// space window. `USER_ACCESSIBLE` is always added to the flags.
+    pub fn map(
+        &mut self,
+        memory: &mut Memory,
+        start_address: VirtAddr,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        check_user_range(start_address, size)?;
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut mapper = self.mapper(memory);
+
+        let mut mapped = 0;
+        for page in page_range(start_address, size) {
+            let frame = match memory.frame_allocator().allocate_frame() {
+                Some(frame) => frame,
+                None => {
+                    self.unmap_partial(memory, start_address, size, mapped);
+                    return Err(AddressSpaceError::FrameAllocationFailed);
+                }
+            };
+            memory.zero_frame(frame);
+            let result = unsafe { mapper.map_to(page, frame, flags, memory.frame_allocator()) };
+            match result {
+                // the TLB only holds entries of the active address space
+                Ok(flush) if self.is_active() => flush.flush(),
+                Ok(flush) => flush.ignore(),
+                Err(err) => {
+                    unsafe { memory.frame_allocator().deallocate_frame(frame) };
+                    self.unmap_partial(memory, start_address, size, mapped);
+                    return Err(err.into());
+                }
+            }
+            mapped += 1;
+        }
+        Ok(())
+    }
+
+    /// Unmaps the first `pages` pages mapped by a failed `map`, releasing
+    /// their frames.
+    fn unmap_partial(
+        &self,
+        memory: &mut Memory,
+        start_address: VirtAddr,
+        size: usize,
+        pages: usize,
+    ) {
+        let mut mapper = self.mapper(memory);
+        for page in page_range(start_address, size).take(pages) {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                if self.is_active() {
+                    flush.flush();
+                } else {
+                    flush.ignore();
+                }
+                unsafe { memory.frame_allocator().deallocate_frame(frame) };
+            }
+        }
+    }
+
+    /// Reserves a range of the user space window to be mapped on demand:
+    /// each page is backed by a zeroed frame when first accessed.
+    /// `USER_ACCESSIBLE` is always added to the flags.
+    pub fn reserve(
+        &mut self,
+        start_address: VirtAddr,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        check_user_range(start_address, size)?;
+        let flags = flags | PageTableFlags::USER_ACCESSIBLE;
+        self.vmas
+            .add(Vma::new(start_address, size, flags, VmaKind::Anonymous))?;
+        Ok(())
+    }
+
+    /// The lazily mapped areas of this address space
+    pub fn vmas(&self) -> &VmaList {
+        &self.vmas
+    }
+
+    /// Translates a virtual address of this address space to its physical address
+    pub fn translate(&self, memory: &Memory, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper(memory).translate_addr(addr)
+    }
+
+    /// Returns the frame mapped to the given page
+    pub fn translate_page(
+        &self,
+        memory: &Memory,
+        page: Page<Size4KiB>,
+    ) -> Result<PhysFrame, TranslateError> {
+        self.mapper(memory).translate_page(page)
+    }
+
+    /// Creates a copy of this address space sharing every user frame.
+    /// Writable pages become read-only copy-on-write pages in both address
+    /// spaces, so frames are only duplicated when written.
+    /// The lazily mapped areas are copied as well.
+    pub fn clone_cow(&mut self, memory: &mut Memory) -> Result<Self, AddressSpaceError> {
+        let mut child = Self::new(memory)?;
+        child.vmas = self.vmas.clone();
+        let mut child_mapper = child.mapper(memory);
+
+        let mut result = Ok(());
+        for_each_leaf(memory, self.l4_frame, |memory, addr, entry| {
+            if result.is_err() {
+                return;
+            }
+            let frame = match entry.frame() {
+                Ok(frame) => frame,
+                Err(_) => return,
+            };
+            let mut flags = entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COW_FLAG;
+                entry.set_flags(flags);
+            }
+            memory.frame_allocator().share_frame(frame);
+            let page = Page::containing_address(addr);
+            let mapped = unsafe {
+                child_mapper.map_to_with_table_flags(
+                    page,
+                    frame,
+                    flags,
+                    USER_TABLE_FLAGS,
+                    memory.frame_allocator(),
+                )
+            };
+            match mapped {
+                // the child isn't active, so there's nothing to flush
+                Ok(flush) => flush.ignore(),
+                Err(err) => {
+                    unsafe { memory.frame_allocator().deallocate_frame(frame) };
+                    result = Err(AddressSpaceError::MappingFailed(err));
+                }
+            }
+        });
+        // the pages of the parent may have lost write access
+        if self.is_active() {
+            tlb::flush_all();
+        }
+
+        match result {
+            Ok(()) => Ok(child),
+            Err(err) => {
+                child.destroy(memory);
+                Err(err)
+            }
+        }
+    }
+
+    /// Resolves a write to a copy-on-write page: the page gets its own copy
+    /// of the frame, unless this address space holds its only reference.
+    /// Returns `false` if the page isn't a copy-on-write page.
+    pub(super) fn handle_cow_fault(&self, memory: &mut Memory, addr: VirtAddr) -> bool {
+        let entry = match leaf_entry(memory, self.l4_frame, addr) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let flags = entry.flags();
+        let frame = match entry.frame() {
+            Ok(frame) if flags.contains(COW_FLAG) => frame,
+            _ => return false,
+        };
+        let flags = (flags - COW_FLAG) | PageTableFlags::WRITABLE;
+
+        if memory.frame_allocator().reference_count(frame) == 1 {
+            entry.set_flags(flags);
+        } else {
+            let copy = match memory.frame_allocator().allocate_frame() {
+                Some(copy) => copy,
+                None => return false,
+            };
+            memory.copy_frame(frame, copy);
+            entry.set_frame(copy, flags);
+            unsafe { memory.frame_allocator().deallocate_frame(frame) };
+        }
+        tlb::flush(addr);
+        true
+    }
+
+    /// Loads this address space in the CPU and registers it in `Memory`,
+    /// so page faults are resolved using its areas.
+    ///
+    /// Unsafe because the address space must not be moved or dropped while
+    /// active, as `Memory` keeps a pointer to it.
+    pub unsafe fn activate(&mut self, memory: &mut Memory) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.l4_frame, flags);
+        memory.set_active_space(Some(NonNull::from(&mut *self)));
+    }
+
+    /// Releases every frame mapped in the user space window, the page tables
+    /// and the level 4 table. If this address space is active, the kernel
+    /// page table is loaded before releasing it.
+    pub fn destroy(self, memory: &mut Memory) {
+        if self.is_active() {
+            memory.activate_kernel_space();
+        }
+
+        let l4_table = unsafe { memory.page_table(self.l4_frame) };
+        for index in USER_L4_ENTRIES {
+            if let Ok(l3_frame) = l4_table[index].frame() {
+                free_table(memory, l3_frame, 3);
+            }
+        }
+        unsafe { memory.frame_allocator().deallocate_frame(self.l4_frame) };
+    }
+
+    pub(super) fn mapper(&self, memory: &Memory) -> OffsetPageTable<'static> {
+        unsafe {
+            OffsetPageTable::new(
+                memory.page_table(self.l4_frame),
+                memory.physical_memory_offset(),
+            )
+        }
+    }
+}
+
+/// Releases the frames mapped by the given table (recursively, for tables
+/// of level 2 or higher) and the table frame itself.
+fn free_table(memory: &mut Memory, table_frame: PhysFrame, level: u8) {
+    let table: &PageTable = unsafe { memory.page_table(table_frame) };
+    for entry in table.iter() {
+        if let Ok(frame) = entry.frame() {
+            if level > 1 {
+                free_table(memory, frame, level - 1);
+            } else {
+                unsafe { memory.frame_allocator().deallocate_frame(frame) };
+            }
+        }
+    }
+    unsafe { memory.frame_allocator().deallocate_frame(table_frame) };
+}
+
+/// Calls `f` for each level 1 entry in use in the user space window of the
+/// given level 4 table, with the virtual address it maps.
+/// Huge pages aren't used in the user space window, so they're skipped.
+fn for_each_leaf<F>(memory: &mut Memory, l4_frame: PhysFrame, mut f: F)
+where
+    F: FnMut(&mut Memory, VirtAddr, &mut PageTableEntry),
+{
+    let l4_table = unsafe { memory.page_table(l4_frame) };
+    for l4_index in USER_L4_ENTRIES {
+        let l3_table = match l4_table[l4_index].frame() {
+            Ok(frame) => unsafe { memory.page_table(frame) },
+            Err(_) => continue,
+        };
+        for (l3_index, l3_entry) in l3_table.iter().enumerate() {
+            let l2_table = match l3_entry.frame() {
+                Ok(frame) => unsafe { memory.page_table(frame) },
+                Err(_) => continue,
+            };
+            for (l2_index, l2_entry) in l2_table.iter().enumerate() {
+                let l1_table = match l2_entry.frame() {
+                    Ok(frame) => unsafe { memory.page_table(frame) },
+                    Err(_) => continue,
+                };
+                for (l1_index, entry) in l1_table.iter_mut().enumerate() {
+                    if entry.is_unused() {
+                        continue;
+                    }
+                    let addr =
+                        (l4_index << 39) | (l3_index << 30) | (l2_index << 21) | (l1_index << 12);
+                    f(memory, VirtAddr::new(addr as u64), entry);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the level 1 entry mapping the given address, if the tables
+/// leading to it exist.
+fn leaf_entry(
+    memory: &Memory,
+    l4_frame: PhysFrame,
+    addr: VirtAddr,
+) -> Option<&'static mut PageTableEntry> {
+    let l4_table = unsafe { memory.page_table(l4_frame) };
+    let l3_table = unsafe { memory.page_table(l4_table[addr.p4_index()].frame().ok()?) };
+    let l2_table = unsafe { memory.page_table(l3_table[addr.p3_index()].frame().ok()?) };
+    let l1_table = unsafe { memory.page_table(l2_table[addr.p2_index()].frame().ok()?) };
+    Some(&mut l1_table[addr.p1_index()])
+}
+
+fn check_user_range(start_address: VirtAddr, size: usize) -> Result<(), AddressSpaceError> {
+    let start = start_address.as_u64();
+    match start.checked_add(size as u64) {
+        Some(end) if start >= USER_SPACE_START && end <= USER_SPACE_END && size > 0 => Ok(()),
+        _ => Err(AddressSpaceError::OutsideUserSpace),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::PageTableFlags;
+    use x86_64::VirtAddr;
+
+    use super::{AddressSpace, USER_SPACE_START};
+    use crate::hal::arch::x86_64::memory::memory;
+
+    #[test_case]
+    fn test_map_and_destroy_releases_frames() {
+        let mut mem = memory().lock();
+        let free_frames = mem.frame_allocator().free_frames();
+
+        let mut space = AddressSpace::new(&mut mem).expect("Unable to create address space");
+        let addr = VirtAddr::new(USER_SPACE_START);
+        space
+            .map(&mut mem, addr, 2 * 4096, PageTableFlags::WRITABLE)
+            .expect("Unable to map user pages");
+        assert!(space.translate(&mem, addr).is_some());
+        assert!(space.translate(&mem, addr + 2 * 4096u64).is_none());
+        // the kernel mappings are shared
+        let kernel_addr = VirtAddr::from_ptr(&free_frames);
+        assert!(space.translate(&mem, kernel_addr).is_some());
+
+        space.destroy(&mut mem);
+        assert_eq!(mem.frame_allocator().free_frames(), free_frames);
+    }
+
+    #[test_case]
+    fn test_later_kernel_mappings_are_shared() {
+        let mut mem = memory().lock();
+        let space = AddressSpace::new(&mut mem).expect("Unable to create address space");
+        let stack = mem.allocate_stack(4096).expect("Unable to allocate stack");
+        assert_eq!(
+            space.translate(&mem, stack.bottom()),
+            mem.translate(stack.bottom())
+        );
+        assert!(space.translate(&mem, stack.bottom()).is_some());
+
+        unsafe { mem.free_stack(stack) };
+        space.destroy(&mut mem);
+    }
+
+    #[test_case]
+    fn test_reserved_pages_are_mapped_on_access() {
+        use x86_64::structures::idt::PageFaultErrorCode;
+
+        let mut mem = memory().lock();
+        let mut space = AddressSpace::new(&mut mem).expect("Unable to create address space");
+        let addr = VirtAddr::new(USER_SPACE_START + 0x10_0000);
+        space
+            .reserve(addr, 16 * 4096, PageTableFlags::WRITABLE)
+            .expect("Unable to reserve user pages");
+        assert!(space.translate(&mem, addr + 4096u64).is_none());
+
+        unsafe { space.activate(&mut mem) };
+        let write = PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::USER_MODE;
+        assert!(mem.handle_page_fault(addr + 4100u64, write));
+        // outside the reserved area
+        assert!(!mem.handle_page_fault(addr + 16 * 4096u64, write));
+        assert!(space.translate(&mem, addr + 4096u64).is_some());
+        assert!(space.translate(&mem, addr).is_none());
+
+        space.destroy(&mut mem);
+    }
+
+    #[test_case]
+    fn test_cow_pages_are_copied_on_write() {
+        use x86_64::structures::idt::PageFaultErrorCode;
+
+        let mut mem = memory().lock();
+        let free_frames = mem.frame_allocator().free_frames();
+        let mut parent = AddressSpace::new(&mut mem).expect("Unable to create address space");
+        let addr = VirtAddr::new(USER_SPACE_START);
+        parent
+            .map(&mut mem, addr, 4096, PageTableFlags::WRITABLE)
+            .expect("Unable to map user pages");
+        let frame = parent.translate(&mem, addr).expect("Page not mapped");
+        let offset = mem.physical_memory_offset();
+        unsafe { *(offset + frame.as_u64()).as_mut_ptr::<u64>() = 42 };
+
+        let mut child = parent
+            .clone_cow(&mut mem)
+            .expect("Unable to clone address space");
+        assert_eq!(child.translate(&mem, addr), parent.translate(&mem, addr));
+
+        unsafe { child.activate(&mut mem) };
+        let write = PageFaultErrorCode::PROTECTION_VIOLATION
+            | PageFaultErrorCode::CAUSED_BY_WRITE
+            | PageFaultErrorCode::USER_MODE;
+        assert!(mem.handle_page_fault(addr, write));
+        let copy = child
+            .translate(&mem, addr)
+            .expect("Page not mapped")
+            .as_u64();
+        assert_ne!(copy, frame.as_u64());
+        assert_eq!(unsafe { *(offset + copy).as_ptr::<u64>() }, 42);
+        // a second write isn't a copy-on-write fault anymore
+        assert!(!mem.handle_page_fault(addr, write));
+
+        child.destroy(&mut mem);
+        parent.destroy(&mut mem);
+        assert_eq!(mem.frame_allocator().free_frames(), free_frames);
+    }
+
+    #[test_case]
+    fn test_map_outside_user_space_fails() {
+        let mut mem = memory().lock();
+        let mut space = AddressSpace::new(&mut mem).expect("Unable to create address space");
+        assert!(space
+            .map(
+                &mut mem,
+                VirtAddr::new(0x1000),
+                4096,
+                PageTableFlags::WRITABLE
+            )
+            .is_err());
+        space.destroy(&mut mem);
+    }
+}
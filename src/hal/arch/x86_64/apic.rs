@@ -0,0 +1,188 @@
+//! Local APIC and I/O APIC drivers.
+//! The Local APIC receives the interrupts for the CPU and must be told when
+//! each one is handled (end of interrupt); the I/O APIC routes the device
+//! interrupts (global system interrupts) to a vector of a Local APIC.
+//! Both are accessed through their registers mapped with `Memory::map_mmio`.
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::mmio::MmioRegion;
+
+/// Vector used by the Local APIC for spurious interrupts, which must not be
+/// acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Size of the Local APIC and I/O APIC register areas
+pub const LOCAL_APIC_SIZE: usize = 0x400;
+pub const IO_APIC_SIZE: usize = 0x20;
+
+/// Model specific register holding the Local APIC base address
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Local APIC registers (offsets)
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// I/O APIC registers: index selection and data window
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+/// Redirection entry bits
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+pub struct LocalApic {
+    registers: MmioRegion,
+}
+
+impl LocalApic {
+    /// Creates the driver for the Local APIC with the given registers.
+    /// Unsafe because the region must map the Local APIC of this CPU.
+    pub unsafe fn new(registers: MmioRegion) -> Self {
+        Self { registers }
+    }
+
+    /// Enables the Local APIC, accepting every interrupt priority
+    pub fn enable(&self) {
+        unsafe {
+            let mut base = Msr::new(IA32_APIC_BASE);
+            let value = base.read();
+            base.write(value | APIC_BASE_ENABLE);
+        }
+        self.registers.write::<u32>(LAPIC_TASK_PRIORITY, 0);
+        self.registers.write::<u32>(
+            LAPIC_SPURIOUS,
+            LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+        );
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.registers.read::<u32>(LAPIC_ID) >> 24) as u8
+    }
+
+    /// Acknowledges the interrupt being handled
+    pub fn end_of_interrupt(&self) {
+        self.registers.write::<u32>(LAPIC_EOI, 0);
+    }
+}
+
+pub struct IoApic {
+    registers: MmioRegion,
+    gsi_base: u32,
+}
+
+impl IoApic {
+    /// Creates the driver for the I/O APIC with the given registers, which
+    /// handles the global system interrupts starting at `gsi_base`.
+    /// Unsafe because the region must map an I/O APIC.
+    pub unsafe fn new(registers: MmioRegion, gsi_base: u32) -> Self {
+        Self {
+            registers,
+            gsi_base,
+        }
+    }
+
+    /// Number of interrupts handled by this I/O APIC
+    pub fn entries(&mut self) -> u32 {
+        ((self.read(IOAPIC_VERSION) >> 16) & 0xff) + 1
+    }
+
+    /// Whether the global system interrupt is handled by this I/O APIC
+    pub fn handles(&mut self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entries()
+    }
+
+    /// Masks every interrupt
+    pub fn mask_all(&mut self) {
+        for index in 0..self.entries() {
+            self.write_entry(index, REDIRECTION_MASKED);
+        }
+    }
+
+    /// Routes a global system interrupt to the given vector of a Local APIC.
+    /// `flags` are the MPS INTI flags (polarity and trigger mode) of the line.
+    pub fn route(&mut self, gsi: u32, vector: u8, apic_id: u8, flags: u16) {
+        assert!(self.handles(gsi), "GSI {} not handled by the I/O APIC", gsi);
+        self.write_entry(
+            gsi - self.gsi_base,
+            redirection_entry(vector, apic_id, flags),
+        );
+    }
+
+    /// Masks or unmasks a global system interrupt
+    pub fn set_masked(&mut self, gsi: u32, masked: bool) {
+        assert!(self.handles(gsi), "GSI {} not handled by the I/O APIC", gsi);
+        let index = gsi - self.gsi_base;
+        let register = IOAPIC_REDIRECTION_TABLE + index * 2;
+        let low = self.read(register) as u64;
+        let low = if masked {
+            low | REDIRECTION_MASKED
+        } else {
+            low & !REDIRECTION_MASKED
+        };
+        self.write(register, low as u32);
+    }
+
+    fn write_entry(&mut self, index: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + index * 2;
+        // mask while the entry is half written
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    fn read(&mut self, register: u32) -> u32 {
+        self.registers.write::<u32>(IOAPIC_REGSEL, register);
+        self.registers.read::<u32>(IOAPIC_WINDOW)
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        self.registers.write::<u32>(IOAPIC_REGSEL, register);
+        self.registers.write::<u32>(IOAPIC_WINDOW, value);
+    }
+}
+
+/// Builds a redirection table entry delivering `vector` to the Local APIC
+/// `apic_id` (fixed delivery, physical destination).
+fn redirection_entry(vector: u8, apic_id: u8, flags: u16) -> u64 {
+    let mut entry = vector as u64 | (apic_id as u64) << 56;
+    // polarity: 0b11 is active low, anything else is the ISA default (high)
+    if flags & 0b11 == 0b11 {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    // trigger mode: 0b11 is level triggered, anything else is edge (ISA default)
+    if (flags >> 2) & 0b11 == 0b11 {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    entry
+}
+
+/// Spurious interrupts don't need to be acknowledged
+pub(crate) extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame,
+) {
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{redirection_entry, REDIRECTION_ACTIVE_LOW, REDIRECTION_LEVEL_TRIGGERED};
+
+    #[test_case]
+    fn test_redirection_entry() {
+        let isa = redirection_entry(33, 0, 0);
+        assert_eq!(isa, 33);
+
+        let pci = redirection_entry(40, 2, 0b1111);
+        assert_eq!(pci & 0xff, 40);
+        assert_eq!(pci >> 56, 2);
+        assert_ne!(pci & REDIRECTION_ACTIVE_LOW, 0);
+        assert_ne!(pci & REDIRECTION_LEVEL_TRIGGERED, 0);
+    }
+}
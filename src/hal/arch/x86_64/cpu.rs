@@ -1,36 +1,441 @@
-use crate::hal::arch::x86_64::pic_interrupts::init_pic;
+use core::fmt;
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr2, Cr3, Cr4, Cr4Flags};
+use x86_64::structures::idt::{InterruptStackFrameValue, PageFaultErrorCode};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
 use crate::kernel::cpu::CPU;
+use crate::kernel::exceptions::{self, ExceptionHandler};
+use crate::kernel::irq::{self, HandlerId, InterruptStream, IrqError, IrqHandler};
+use crate::kernel::{time, TIMER_FREQUENCY};
+use crate::kprintln;
 
 use super::gdt::init_gdt;
+use super::interrupt_controller::{
+    disable_irq, enable_irq, init_interrupt_controller, InterruptMode,
+};
 use super::interrupts::init_idt;
+use super::pic_interrupts::{timer_handler, InterruptIndex};
+use super::pit::{calibrate_tsc, set_timer_frequency};
 
-#[derive(Debug)]
+/// Architectural exceptions, with their vector numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
 pub enum InterruptionType {
-    DoubleFault,
-    Breakpoint,
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    ControlProtection = 21,
+    HypervisorInjection = 28,
+    VmmCommunication = 29,
+    SecurityException = 30,
+}
+
+impl InterruptionType {
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_vector(vector: u8) -> Option<Self> {
+        use InterruptionType::*;
+        let kind = match vector {
+            0 => DivideError,
+            1 => Debug,
+            2 => NonMaskableInterrupt,
+            3 => Breakpoint,
+            4 => Overflow,
+            5 => BoundRangeExceeded,
+            6 => InvalidOpcode,
+            7 => DeviceNotAvailable,
+            8 => DoubleFault,
+            10 => InvalidTss,
+            11 => SegmentNotPresent,
+            12 => StackSegmentFault,
+            13 => GeneralProtectionFault,
+            14 => PageFault,
+            16 => X87FloatingPoint,
+            17 => AlignmentCheck,
+            18 => MachineCheck,
+            19 => SimdFloatingPoint,
+            20 => Virtualization,
+            21 => ControlProtection,
+            28 => HypervisorInjection,
+            29 => VmmCommunication,
+            30 => SecurityException,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    /// Whether the CPU pushes an error code for the exception
+    pub fn has_error_code(self) -> bool {
+        match self {
+            InterruptionType::DoubleFault
+            | InterruptionType::PageFault
+            | InterruptionType::AlignmentCheck
+            | InterruptionType::ControlProtection
+            | InterruptionType::VmmCommunication
+            | InterruptionType::SecurityException => true,
+            _ => self.has_selector_error_code(),
+        }
+    }
+
+    /// Whether the error code pushed by the CPU is a segment selector index
+    fn has_selector_error_code(self) -> bool {
+        match self {
+            InterruptionType::InvalidTss
+            | InterruptionType::SegmentNotPresent
+            | InterruptionType::StackSegmentFault
+            | InterruptionType::GeneralProtectionFault => true,
+            _ => false,
+        }
+    }
+}
+
+/// Descriptor table referenced by a selector error code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// Error code pushed by the CPU, decoded according to the exception
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Selector of the segment or gate that caused the exception
+    /// (a null selector means the exception isn't related to a segment)
+    Selector {
+        external: bool,
+        table: DescriptorTable,
+        index: u16,
+    },
+    PageFault(PageFaultErrorCode),
+    /// Error codes without any meaning (always `0`) or unknown ones
+    Raw(u64),
 }
 
+impl ErrorCode {
+    pub fn decode(kind: InterruptionType, error_code: u64) -> Self {
+        if kind == InterruptionType::PageFault {
+            ErrorCode::PageFault(PageFaultErrorCode::from_bits_truncate(error_code))
+        } else if kind.has_selector_error_code() {
+            let table = match (error_code >> 1) & 0b11 {
+                0b00 => DescriptorTable::Gdt,
+                0b10 => DescriptorTable::Ldt,
+                _ => DescriptorTable::Idt,
+            };
+            ErrorCode::Selector {
+                external: error_code & 1 != 0,
+                table,
+                index: ((error_code >> 3) & 0x1fff) as u16,
+            }
+        } else {
+            ErrorCode::Raw(error_code)
+        }
+    }
+}
+
+/// General purpose registers of the interrupted code, in the order the
+/// exception entry stubs save them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// State of the CPU when an exception happened.
+/// The exception handler can change the instruction pointer, the stack
+/// pointer and the general purpose registers: the execution resumes with
+/// them. Changes to the other fields are ignored.
+#[derive(Debug, Clone, Copy)]
 pub struct InterruptionDetails {
-    pub error_code: Option<u64>,
+    pub kind: InterruptionType,
+    pub error_code: Option<ErrorCode>,
+    pub instruction_pointer: VirtAddr,
+    pub stack_pointer: VirtAddr,
+    pub code_segment: u64,
+    pub stack_segment: u64,
+    pub cpu_flags: u64,
+    pub registers: Registers,
+    pub cr0: Cr0Flags,
+    /// Last page fault address
+    pub cr2: VirtAddr,
+    /// Active level 4 table
+    pub cr3: PhysFrame,
+    pub cr4: Cr4Flags,
+}
+
+impl InterruptionDetails {
+    /// Captures the state of the CPU from an exception handler
+    pub fn capture(
+        kind: InterruptionType,
+        stack_frame: &InterruptStackFrameValue,
+        error_code: Option<u64>,
+        registers: Registers,
+    ) -> Self {
+        Self {
+            kind,
+            error_code: error_code.map(|code| ErrorCode::decode(kind, code)),
+            instruction_pointer: stack_frame.instruction_pointer,
+            stack_pointer: stack_frame.stack_pointer,
+            code_segment: stack_frame.code_segment,
+            stack_segment: stack_frame.stack_segment,
+            cpu_flags: stack_frame.cpu_flags,
+            registers,
+            cr0: Cr0::read(),
+            cr2: Cr2::read(),
+            cr3: Cr3::read().0,
+            cr4: Cr4::read(),
+        }
+    }
+}
+
+impl fmt::Display for InterruptionDetails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "EXCEPTION: {:?} (vector {})",
+            self.kind,
+            self.kind.vector()
+        )?;
+        if let Some(error_code) = self.error_code {
+            writeln!(f, "  error code: {:?}", error_code)?;
+        }
+        writeln!(
+            f,
+            "  rip: {:#018x}  cs: {:#06x}  rflags: {:#018x}",
+            self.instruction_pointer.as_u64(),
+            self.code_segment,
+            self.cpu_flags
+        )?;
+        writeln!(
+            f,
+            "  rsp: {:#018x}  ss: {:#06x}",
+            self.stack_pointer.as_u64(),
+            self.stack_segment
+        )?;
+        let regs = &self.registers;
+        writeln!(
+            f,
+            "  rax: {:#018x}  rbx: {:#018x}  rcx: {:#018x}",
+            regs.rax, regs.rbx, regs.rcx
+        )?;
+        writeln!(
+            f,
+            "  rdx: {:#018x}  rsi: {:#018x}  rdi: {:#018x}",
+            regs.rdx, regs.rsi, regs.rdi
+        )?;
+        writeln!(
+            f,
+            "  rbp: {:#018x}  r8:  {:#018x}  r9:  {:#018x}",
+            regs.rbp, regs.r8, regs.r9
+        )?;
+        writeln!(
+            f,
+            "  r10: {:#018x}  r11: {:#018x}  r12: {:#018x}",
+            regs.r10, regs.r11, regs.r12
+        )?;
+        writeln!(
+            f,
+            "  r13: {:#018x}  r14: {:#018x}  r15: {:#018x}",
+            regs.r13, regs.r14, regs.r15
+        )?;
+        writeln!(
+            f,
+            "  cr2: {:#018x}  cr3: {:#018x}",
+            self.cr2.as_u64(),
+            self.cr3.start_address().as_u64()
+        )?;
+        writeln!(f, "  cr0: {:?}", self.cr0)?;
+        write!(f, "  cr4: {:?}", self.cr4)
+    }
 }
 
-pub struct X86CPU {}
+/// Loads the GDT and the IDT, so CPU exceptions are handled.
+/// Hardware interrupts stay disabled: `CPU::init` sets them up.
+pub fn init_exceptions() {
+    init_gdt();
+    init_idt();
+}
+
+pub struct X86CPU {
+    interrupt_mode: InterruptMode,
+}
 
 impl X86CPU {
+    /// Creates a CPU using the APIC for hardware interrupts (or the 8259 PIC
+    /// if the APIC isn't available).
     pub fn new() -> Self {
-        Self {}
+        Self::with_interrupt_mode(InterruptMode::Apic)
+    }
+
+    /// Creates a CPU using the given interrupt controller
+    pub fn with_interrupt_mode(interrupt_mode: InterruptMode) -> Self {
+        Self { interrupt_mode }
     }
 }
 
 impl CPU for X86CPU {
     fn init(&self) {
-        init_gdt();
-        init_pic();
-        init_idt();
-        x86_64::instructions::interrupts::enable();
+        init_exceptions();
+        init_interrupt_controller(self.interrupt_mode);
+        let tick_period = set_timer_frequency(TIMER_FREQUENCY);
+        let tsc_frequency = calibrate_tsc();
+        match tsc_frequency {
+            Some(frequency) => kprintln!("TSC frequency: {} MHz", frequency / 1_000_000),
+            None => kprintln!("TSC calibration failed, using the timer ticks as clock"),
+        }
+        time::init(tick_period, tsc_frequency);
+        self.register_interrupt_handler(InterruptIndex::Timer.line(), timer_handler)
+            .expect("Unable to register the timer handler");
+        self.enable_interrupts();
     }
 
     fn hlt(&self) {
         x86_64::instructions::hlt();
     }
+
+    fn idle(&self) {
+        x86_64::instructions::interrupts::enable_and_hlt();
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        x86_64::instructions::interrupts::are_enabled()
+    }
+
+    fn disable_interrupts(&self) {
+        x86_64::instructions::interrupts::disable();
+    }
+
+    fn enable_interrupts(&self) {
+        x86_64::instructions::interrupts::enable();
+    }
+
+    fn register_interrupt_handler(
+        &self,
+        line: u8,
+        handler: IrqHandler,
+    ) -> Result<HandlerId, IrqError> {
+        let id = irq::register_handler(line, handler)?;
+        enable_irq(line);
+        Ok(id)
+    }
+
+    fn register_interrupt_stream(&self, line: u8) -> Result<InterruptStream, IrqError> {
+        let stream = irq::register_stream(line)?;
+        enable_irq(line);
+        Ok(stream)
+    }
+
+    fn unregister_interrupt_handler(&self, id: HandlerId) -> Result<(), IrqError> {
+        if irq::unregister(id)? {
+            disable_irq(id.line());
+        }
+        Ok(())
+    }
+
+    fn set_exception_handler(&self, handler: ExceptionHandler) {
+        exceptions::set_handler(handler);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::idt::PageFaultErrorCode;
+
+    use super::{DescriptorTable, ErrorCode, InterruptionType};
+
+    #[test_case]
+    fn test_selector_error_code_is_decoded() {
+        // IDT entry 13, raised by an external event
+        let code = ErrorCode::decode(InterruptionType::GeneralProtectionFault, 13 << 3 | 0b011);
+        assert_eq!(
+            code,
+            ErrorCode::Selector {
+                external: true,
+                table: DescriptorTable::Idt,
+                index: 13,
+            }
+        );
+        let code = ErrorCode::decode(InterruptionType::SegmentNotPresent, 5 << 3 | 0b100);
+        assert_eq!(
+            code,
+            ErrorCode::Selector {
+                external: false,
+                table: DescriptorTable::Ldt,
+                index: 5,
+            }
+        );
+    }
+
+    #[test_case]
+    fn test_other_error_codes_are_decoded() {
+        let code = ErrorCode::decode(InterruptionType::PageFault, 0b11);
+        assert_eq!(
+            code,
+            ErrorCode::PageFault(
+                PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE
+            )
+        );
+        assert_eq!(
+            ErrorCode::decode(InterruptionType::AlignmentCheck, 0),
+            ErrorCode::Raw(0)
+        );
+    }
+
+    #[test_case]
+    fn test_vectors_are_decoded() {
+        for vector in 0..32 {
+            if let Some(kind) = InterruptionType::from_vector(vector) {
+                assert_eq!(kind.vector(), vector);
+            }
+        }
+        assert_eq!(
+            InterruptionType::from_vector(14),
+            Some(InterruptionType::PageFault)
+        );
+        assert_eq!(InterruptionType::from_vector(9), None);
+        assert!(InterruptionType::DoubleFault.has_error_code());
+        assert!(InterruptionType::GeneralProtectionFault.has_error_code());
+        assert!(!InterruptionType::Breakpoint.has_error_code());
+        assert_eq!(
+            InterruptionType::from_vector(21),
+            Some(InterruptionType::ControlProtection)
+        );
+        assert!(InterruptionType::ControlProtection.has_error_code());
+        assert!(!InterruptionType::HypervisorInjection.has_error_code());
+        assert!(InterruptionType::VmmCommunication.has_error_code());
+    }
 }
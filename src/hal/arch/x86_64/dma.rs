@@ -0,0 +1,127 @@
+//! Buffers for direct memory access.
+//! Devices doing bus-mastering DMA (virtio, ATA...) access memory by physical
+//! address, so their buffers must be physically contiguous, and devices with
+//! 32 bits addressing also need them below 4GiB.
+//!
+//! `Memory::allocate_dma` takes a contiguous range of frames from the frame
+//! allocator; the buffer is accessed through the physical memory mapping, so
+//! no page table update is needed.
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Physical memory the device can address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaZone {
+    /// Any physical address
+    Any,
+    /// Addresses below 4GiB, for devices with 32 bits addressing
+    Below4GiB,
+}
+
+impl DmaZone {
+    /// Highest physical address (exclusive) of the zone, if limited
+    pub fn limit(&self) -> Option<PhysAddr> {
+        match self {
+            DmaZone::Any => None,
+            DmaZone::Below4GiB => Some(PhysAddr::new(0x1_0000_0000)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DmaError {
+    /// The requested size is zero
+    InvalidSize,
+    /// There's no free contiguous range large enough in the zone
+    NoContiguousMemory,
+}
+
+/// A zeroed, physically contiguous buffer allocated by `Memory::allocate_dma`
+/// and released by `Memory::free_dma`.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    frames: PhysFrameRange,
+    virt: VirtAddr,
+}
+
+impl DmaBuffer {
+    pub(super) fn new(frames: PhysFrameRange, virt: VirtAddr) -> Self {
+        Self { frames, virt }
+    }
+
+    /// Physical address of the buffer, to be given to the device
+    pub fn phys(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+
+    /// Virtual address of the buffer, to be used by the kernel
+    pub fn virt(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// Size of the buffer in bytes (a multiple of the frame size)
+    pub fn size(&self) -> usize {
+        (self.frames.end.start_address() - self.frames.start.start_address()) as usize
+    }
+
+    pub fn frames(&self) -> PhysFrameRange {
+        self.frames
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.size()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.size()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::PhysFrame;
+
+    use crate::hal::arch::x86_64::memory::memory;
+
+    use super::DmaZone;
+
+    #[test_case]
+    fn test_dma_buffer_is_physically_contiguous() {
+        let mut mem = memory().lock();
+        let free_frames = mem.frame_allocator().free_frames();
+
+        let mut buffer = mem
+            .allocate_dma(5 * 4096 + 1, DmaZone::Any)
+            .expect("Unable to allocate DMA buffer");
+        assert_eq!(buffer.size(), 6 * 4096);
+        assert_eq!(buffer.phys().as_u64() % 4096, 0);
+        for page in 0..6u64 {
+            let offset = page * 4096;
+            assert_eq!(
+                mem.translate(buffer.virt() + offset),
+                Some(buffer.phys() + offset)
+            );
+            // the frame was allocated for the buffer only
+            let frame = PhysFrame::containing_address(buffer.phys() + offset);
+            assert_eq!(mem.frame_allocator().reference_count(frame), 1);
+        }
+        assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+        buffer.as_mut_slice()[4096] = 0xff;
+
+        unsafe { mem.free_dma(buffer) };
+        assert_eq!(mem.frame_allocator().free_frames(), free_frames);
+    }
+
+    #[test_case]
+    fn test_dma_buffer_below_4gib() {
+        let mut mem = memory().lock();
+        let buffer = mem
+            .allocate_dma(16 * 4096, DmaZone::Below4GiB)
+            .expect("Unable to allocate DMA buffer");
+        let end = buffer.phys() + buffer.size() as u64;
+        assert!(end <= DmaZone::Below4GiB.limit().unwrap());
+        unsafe { mem.free_dma(buffer) };
+
+        assert!(mem.allocate_dma(0, DmaZone::Any).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! Physical memory manager.
+//! Keeps track of every 4KiB physical frame using a bitmap, where each
+//! bit tells whether the frame is in use (`1`) or free (`0`).
+//!
+//! Frames can also be shared (e.g. by copy-on-write mappings): for each frame
+//! the allocator keeps the number of extra references, and a shared frame is
+//! only released once every reference is deallocated.
+//!
+//! The bitmap and the reference counts live in physical memory: they are
+//! placed at the beginning of the first usable region large enough to hold
+//! them, and the frames they occupy are marked as used. They are accessed
+//! through the physical memory mapping created by the boot loader, so the
+//! allocator doesn't depend on the kernel heap.
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Size in bytes of a physical frame
+pub const FRAME_SIZE: u64 = 4096;
+
+const BITS_PER_ENTRY: usize = 64;
+
+/// Bitmap based frame allocator built from the boot loader `MemoryMap`.
+/// It supports freeing frames and allocating physically contiguous
+/// ranges of frames.
+pub struct PhysicalFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// Number of references to each frame besides the first one
+    shares: &'static mut [u16],
+    /// Number of frames covered by the bitmap
+    frame_count: usize,
+    /// Number of frames available for allocation (usable frames minus
+    /// the ones used by the bitmap)
+    total_frames: usize,
+    free_frames: usize,
+    /// Index of the bitmap entry where the next search starts
+    next_entry: usize,
+}
+
+impl PhysicalFrameAllocator {
+    /// Creates the allocator using the usable regions of the given memory map.
+    /// The `physical_memory_offset` is the virtual address where the boot loader
+    /// mapped the physical memory.
+    pub fn new(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let highest_address = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .expect("No usable memory region available");
+        let frame_count = (highest_address / FRAME_SIZE) as usize;
+        let entries = (frame_count + BITS_PER_ENTRY - 1) / BITS_PER_ENTRY;
+        let bitmap_size = entries * core::mem::size_of::<u64>();
+        let shares_size = frame_count * core::mem::size_of::<u16>();
+        let metadata_frames = frames_for(bitmap_size + shares_size);
+
+        // find a place to store the bitmap and reference counts
+        let metadata_region = usable_regions()
+            .find(|r| {
+                (r.range.end_frame_number - r.range.start_frame_number) as usize >= metadata_frames
+            })
+            .expect("No usable memory region large enough for the frame bitmap");
+        let metadata_start = metadata_region.range.start_frame_number as usize;
+
+        let (bitmap, shares) = unsafe {
+            let virt = physical_memory_offset + metadata_region.range.start_addr();
+            (
+                core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u64>(), entries),
+                core::slice::from_raw_parts_mut(
+                    (virt + bitmap_size).as_mut_ptr::<u16>(),
+                    frame_count,
+                ),
+            )
+        };
+        // everything is in use until the memory map says otherwise
+        for entry in bitmap.iter_mut() {
+            *entry = u64::MAX;
+        }
+        for share in shares.iter_mut() {
+            *share = 0;
+        }
+
+        let mut allocator = Self {
+            bitmap,
+            shares,
+            frame_count,
+            total_frames: 0,
+            free_frames: 0,
+            next_entry: 0,
+        };
+        for region in usable_regions() {
+            let start = region.range.start_frame_number as usize;
+            let end = region.range.end_frame_number as usize;
+            for index in start..end {
+                if index < metadata_start || index >= metadata_start + metadata_frames {
+                    allocator.clear_bit(index);
+                    allocator.total_frames += 1;
+                }
+            }
+        }
+        allocator.free_frames = allocator.total_frames;
+        allocator
+    }
+
+    /// Number of frames managed by this allocator
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Number of frames still available for allocation
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of frames currently allocated
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Adds a reference to an allocated frame, so it's only released after
+    /// one more call to `deallocate_frame`.
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame);
+        assert!(
+            index < self.frame_count && self.is_used(index),
+            "Sharing frame not in use: {:?}",
+            frame
+        );
+        self.shares[index] = self.shares[index]
+            .checked_add(1)
+            .expect("Too many references to frame");
+    }
+
+    /// Number of references to the given frame (0 if it's free)
+    pub fn reference_count(&self, frame: PhysFrame) -> usize {
+        let index = frame_index(frame);
+        if index < self.frame_count && self.is_used(index) {
+            self.shares[index] as usize + 1
+        } else {
+            0
+        }
+    }
+
+    /// Allocates `count` physically contiguous frames.
+    /// Returns `None` if there's no free range large enough.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange> {
+        self.find_contiguous(count, self.frame_count)
+    }
+
+    /// Allocates `count` physically contiguous frames, all of them below
+    /// the given physical address (exclusive).
+    /// Returns `None` if there's no free range large enough.
+    pub fn allocate_contiguous_below(
+        &mut self,
+        count: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysFrameRange> {
+        self.find_contiguous(count, (limit.as_u64() / FRAME_SIZE) as usize)
+    }
+
+    /// Allocates the first free run of `count` frames with indexes below `frame_limit`
+    fn find_contiguous(&mut self, count: usize, frame_limit: usize) -> Option<PhysFrameRange> {
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+
+        let end = self.frame_count.min(frame_limit);
+        let mut run_start = 0;
+        let mut run_length = 0;
+        let mut index = 0;
+        while index < end {
+            // skip fully used entries at once
+            if index % BITS_PER_ENTRY == 0 && self.bitmap[index / BITS_PER_ENTRY] == u64::MAX {
+                run_length = 0;
+                index += BITS_PER_ENTRY;
+                continue;
+            }
+
+            if self.is_used(index) {
+                run_length = 0;
+            } else {
+                if run_length == 0 {
+                    run_start = index;
+                }
+                run_length += 1;
+                if run_length == count {
+                    for frame in run_start..run_start + count {
+                        self.set_bit(frame);
+                    }
+                    self.free_frames -= count;
+                    let start = frame_at(run_start);
+                    return Some(PhysFrame::range(start, start + count as u64));
+                }
+            }
+            index += 1;
+        }
+        None
+    }
+
+    /// Returns a range of frames, previously allocated with `allocate_contiguous`,
+    /// to the allocator.
+    ///
+    /// Unsafe because the caller must guarantee the frames are no longer in use.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_ENTRY] & (1 << (index % BITS_PER_ENTRY)) != 0
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_ENTRY] |= 1 << (index % BITS_PER_ENTRY);
+    }
+
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_ENTRY] &= !(1 << (index % BITS_PER_ENTRY));
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for PhysicalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        let entries = self.bitmap.len();
+        for offset in 0..entries {
+            let entry = (self.next_entry + offset) % entries;
+            let bits = self.bitmap[entry];
+            if bits != u64::MAX {
+                let index = entry * BITS_PER_ENTRY + (!bits).trailing_zeros() as usize;
+                if index >= self.frame_count {
+                    continue;
+                }
+                self.set_bit(index);
+                self.free_frames -= 1;
+                self.next_entry = entry;
+                return Some(frame_at(index));
+            }
+        }
+        None
+    }
+}
+
+/// Deallocating a shared frame only drops one of its references.
+impl FrameDeallocator<Size4KiB> for PhysicalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame);
+        assert!(
+            index < self.frame_count && self.is_used(index),
+            "Freeing frame not in use: {:?}",
+            frame
+        );
+        if self.shares[index] > 0 {
+            self.shares[index] -= 1;
+            return;
+        }
+        self.clear_bit(index);
+        self.free_frames += 1;
+        // freed frames are reused first
+        if index / BITS_PER_ENTRY < self.next_entry {
+            self.next_entry = index / BITS_PER_ENTRY;
+        }
+    }
+}
+
+/// Number of frames needed to hold `size` bytes
+pub fn frames_for(size: usize) -> usize {
+    (size + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::FrameDeallocator;
+
+    use crate::hal::arch::x86_64::memory::memory;
+
+    use super::frame_index;
+
+    const RUN: u64 = 16;
+
+    #[test_case]
+    fn test_contiguous_allocation_skips_fragmented_frames() {
+        let mut mem = memory().lock();
+        let allocator = mem.frame_allocator();
+        let free_frames = allocator.free_frames();
+
+        // free every other frame of a run: its holes can't hold two frames
+        let run = allocator
+            .allocate_contiguous(RUN as usize)
+            .expect("Unable to allocate frames");
+        let is_hole = |offset: u64| offset % 2 == 1 && offset < RUN - 2;
+        let mut holes = 0;
+        for offset in (0..RUN).filter(|&offset| is_hole(offset)) {
+            unsafe { allocator.deallocate_frame(run.start + offset) };
+            holes += 1;
+        }
+        for offset in 0..RUN {
+            let frame = run.start + offset;
+            assert_eq!(allocator.is_used(frame_index(frame)), !is_hole(offset));
+        }
+
+        let range = allocator
+            .allocate_contiguous(2)
+            .expect("Unable to allocate frames");
+        assert_eq!(range.end - range.start, 2);
+        assert!(range.start >= run.end || range.end <= run.start);
+        for frame in range {
+            assert!(allocator.is_used(frame_index(frame)));
+            assert_eq!(allocator.reference_count(frame), 1);
+        }
+        assert_eq!(
+            allocator.free_frames(),
+            free_frames - (RUN as usize - holes) - 2
+        );
+
+        unsafe {
+            allocator.deallocate_contiguous(range);
+            for offset in (0..RUN).filter(|&offset| !is_hole(offset)) {
+                allocator.deallocate_frame(run.start + offset);
+            }
+        }
+        assert_eq!(allocator.free_frames(), free_frames);
+    }
+}
@@ -0,0 +1,82 @@
+//! QEMU firmware configuration device (fw_cfg).
+//! The boot loader doesn't pass a command line to the kernel, so boot options
+//! are given as fw_cfg files instead: `-fw_cfg name=opt/hendrix/<option>,string=<value>`.
+//!
+//! An item is selected by writing its key to the selector port, then its
+//! content is read byte by byte from the data port. The file directory lists
+//! the name and key of every file; its numbers are big endian.
+use x86_64::instructions::port::Port;
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+const SIGNATURE_KEY: u16 = 0x0000;
+const FILE_DIR_KEY: u16 = 0x0019;
+const SIGNATURE: [u8; 4] = *b"QEMU";
+
+/// Size of the (NUL padded) name of a file directory entry
+const FILE_NAME_SIZE: usize = 56;
+
+/// Reads the file with the given name into `buffer`, truncating it if it
+/// doesn't fit. Returns the number of bytes read, or `None` if the file
+/// doesn't exist or the machine has no fw_cfg device.
+/// The device isn't locked, so it's meant to be read at boot only.
+pub fn read_file(name: &str, buffer: &mut [u8]) -> Option<usize> {
+    if !is_present() {
+        return None;
+    }
+    select(FILE_DIR_KEY);
+    let count = read_u32();
+    for _ in 0..count {
+        let size = read_u32() as usize;
+        let key = read_u16();
+        let _reserved = read_u16();
+        let mut file_name = [0; FILE_NAME_SIZE];
+        read_bytes(&mut file_name);
+        if name_matches(&file_name, name) {
+            let len = size.min(buffer.len());
+            select(key);
+            read_bytes(&mut buffer[..len]);
+            return Some(len);
+        }
+    }
+    None
+}
+
+fn is_present() -> bool {
+    select(SIGNATURE_KEY);
+    let mut signature = [0; 4];
+    read_bytes(&mut signature);
+    signature == SIGNATURE
+}
+
+fn name_matches(file_name: &[u8; FILE_NAME_SIZE], name: &str) -> bool {
+    let len = file_name
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(FILE_NAME_SIZE);
+    &file_name[..len] == name.as_bytes()
+}
+
+fn select(key: u16) {
+    unsafe { Port::<u16>::new(SELECTOR_PORT).write(key) };
+}
+
+fn read_bytes(buffer: &mut [u8]) {
+    let mut data = Port::<u8>::new(DATA_PORT);
+    for byte in buffer.iter_mut() {
+        *byte = unsafe { data.read() };
+    }
+}
+
+fn read_u16() -> u16 {
+    let mut bytes = [0; 2];
+    read_bytes(&mut bytes);
+    u16::from_be_bytes(bytes)
+}
+
+fn read_u32() -> u32 {
+    let mut bytes = [0; 4];
+    read_bytes(&mut bytes);
+    u32::from_be_bytes(bytes)
+}
@@ -16,12 +16,16 @@ use x86_64::instructions::segmentation::set_cs;
 use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::VirtAddr;
 
 use crate::kprintln;
 
+use super::memory::memory;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// Size of the stack used when a double fault interruption happens
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
 /// TODO describe what is each of those SegmentSelectors about
 struct Selectors {
     code_selector: SegmentSelector,
@@ -31,15 +35,13 @@ struct Selectors {
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            /// Defines the memory address for the stack to be used when
-            /// a double fault interruption happens.
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+        // the stack has a guard page, so overflowing it while handling
+        // a double fault doesn't corrupt the memory next to it
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = memory()
+            .lock()
+            .allocate_stack(DOUBLE_FAULT_STACK_SIZE)
+            .expect("Unable to allocate the double fault stack")
+            .top();
         tss
     };
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
@@ -60,6 +62,8 @@ lazy_static! {
 /// assigning the interrupt_stack_table to be used when a double fault
 /// interrupt happens, configuring the GDT to use the newly created
 /// TSS and finally loading the GDT.
+/// The global `Memory` must be initialized, as it provides the double
+/// fault stack.
 pub fn init_gdt() {
     kprintln!("Initializing GDT");
     GDT.0.load();
@@ -0,0 +1,193 @@
+//! Interrupt controller selection.
+//! Hardware interrupts are delivered either by the legacy 8259 PICs or by the
+//! Local APIC + I/O APIC, chosen when the CPU is initialized. Both deliver the
+//! same vectors (`IRQ_BASE_VECTOR` + IRQ line), so the handlers don't depend
+//! on the controller in use, apart from acknowledging the interrupt through
+//! `end_of_interrupt`.
+//!
+//! Every line starts masked and is enabled by `enable_irq` when a handler
+//! is registered for it.
+//!
+//! The APIC is discovered through the ACPI MADT; if it can't be found the
+//! PICs are used instead.
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+
+use crate::kprintln;
+
+use super::acpi::{find_madt, Madt};
+use super::apic::{IoApic, LocalApic, IO_APIC_SIZE, LOCAL_APIC_SIZE};
+use super::memory::memory;
+use super::pic_interrupts::{
+    disable_pic, init_pic, pic_end_of_interrupt, set_pic_line_masked, IRQ_BASE_VECTOR,
+};
+
+/// Interrupt controller used to deliver hardware interrupts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptMode {
+    /// Legacy 8259 PICs
+    Pic,
+    /// Local APIC and I/O APIC
+    Apic,
+}
+
+impl InterruptMode {
+    /// Parses the name of a mode (`pic` or `apic`), as given in boot options.
+    /// Trailing NUL and new line characters are ignored.
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        let len = name
+            .iter()
+            .rposition(|&byte| byte != 0 && byte != b'\n')
+            .map_or(0, |last| last + 1);
+        match &name[..len] {
+            b"pic" => Some(InterruptMode::Pic),
+            b"apic" => Some(InterruptMode::Apic),
+            _ => None,
+        }
+    }
+}
+
+enum Controller {
+    Pic,
+    Apic {
+        local_apic: LocalApic,
+        io_apic: Mutex<IoApic>,
+        /// Wiring of the ISA IRQ lines
+        madt: Madt,
+    },
+}
+
+static CONTROLLER: OnceCell<Controller> = OnceCell::uninit();
+
+/// Initializes the interrupt controller for the given mode, falling back to
+/// the PICs if the APIC isn't available. Returns the mode actually in use.
+/// Interrupts must be disabled.
+pub fn init_interrupt_controller(mode: InterruptMode) -> InterruptMode {
+    let controller = match mode {
+        InterruptMode::Apic => init_apic().unwrap_or_else(|| {
+            kprintln!("APIC not available, using the 8259 PIC");
+            init_pic_controller()
+        }),
+        InterruptMode::Pic => init_pic_controller(),
+    };
+    CONTROLLER
+        .try_init_once(|| controller)
+        .expect("Interrupt controller already initialized");
+    interrupt_mode().unwrap()
+}
+
+/// Returns the interrupt controller in use, if already initialized
+pub fn interrupt_mode() -> Option<InterruptMode> {
+    CONTROLLER
+        .try_get()
+        .ok()
+        .map(|controller| match controller {
+            Controller::Pic => InterruptMode::Pic,
+            Controller::Apic { .. } => InterruptMode::Apic,
+        })
+}
+
+/// Acknowledges the hardware interrupt with the given vector
+pub fn end_of_interrupt(vector: u8) {
+    match CONTROLLER.try_get() {
+        Ok(Controller::Apic { local_apic, .. }) => local_apic.end_of_interrupt(),
+        _ => pic_end_of_interrupt(vector),
+    }
+}
+
+/// Starts delivering the interrupts of the given IRQ line, to the vector
+/// `IRQ_BASE_VECTOR + line`. Does nothing if the controller isn't initialized.
+pub fn enable_irq(line: u8) {
+    match CONTROLLER.try_get() {
+        Ok(Controller::Apic {
+            local_apic,
+            io_apic,
+            madt,
+        }) => {
+            let route = madt.isa_route(line);
+            io_apic.lock().route(
+                route.gsi,
+                IRQ_BASE_VECTOR + line,
+                local_apic.id(),
+                route.flags,
+            );
+        }
+        Ok(Controller::Pic) => set_pic_line_masked(line, false),
+        Err(_) => {}
+    }
+}
+
+/// Stops delivering the interrupts of the given IRQ line
+pub fn disable_irq(line: u8) {
+    match CONTROLLER.try_get() {
+        Ok(Controller::Apic { io_apic, madt, .. }) => {
+            io_apic.lock().set_masked(madt.isa_route(line).gsi, true);
+        }
+        Ok(Controller::Pic) => set_pic_line_masked(line, true),
+        Err(_) => {}
+    }
+}
+
+fn init_pic_controller() -> Controller {
+    // lines are unmasked as handlers are registered
+    disable_pic();
+    Controller::Pic
+}
+
+fn init_apic() -> Option<Controller> {
+    kprintln!("Initializing APIC");
+    let mut mem = memory().lock();
+    let madt = find_madt(mem.physical_memory_offset())?;
+    let io_apic_entry = madt.io_apic?;
+
+    let local_apic = mem.map_mmio(madt.local_apic, LOCAL_APIC_SIZE).ok()?;
+    let io_apic = match mem.map_mmio(io_apic_entry.address, IO_APIC_SIZE) {
+        Ok(io_apic) => io_apic,
+        Err(_) => {
+            mem.unmap_mmio(local_apic);
+            return None;
+        }
+    };
+    drop(mem);
+
+    // the PICs may still raise (spurious) interrupts, so they're remapped
+    // away from the exceptions before being masked
+    if madt.has_legacy_pics {
+        disable_pic();
+    }
+
+    let (local_apic, mut io_apic) = unsafe {
+        (
+            LocalApic::new(local_apic),
+            IoApic::new(io_apic, io_apic_entry.gsi_base),
+        )
+    };
+    local_apic.enable();
+    io_apic.mask_all();
+
+    Some(Controller::Apic {
+        local_apic,
+        io_apic: Mutex::new(io_apic),
+        madt,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InterruptMode;
+
+    #[test_case]
+    fn test_mode_from_name() {
+        assert_eq!(InterruptMode::from_name(b"pic"), Some(InterruptMode::Pic));
+        assert_eq!(
+            InterruptMode::from_name(b"apic\n"),
+            Some(InterruptMode::Apic)
+        );
+        assert_eq!(
+            InterruptMode::from_name(b"pic\0\0"),
+            Some(InterruptMode::Pic)
+        );
+        assert_eq!(InterruptMode::from_name(b"x2apic"), None);
+        assert_eq!(InterruptMode::from_name(b""), None);
+    }
+}
@@ -5,47 +5,83 @@
 //!
 //! This module also define the callbacks to be called directly by the processor
 //! when any interruption happens.
-//! To connect back to the CPU object, a CPU instance needs to be passed to
-//! the `init_idt` function will hold a static reference to the given object.
-//! The callback functions on their turn will just encapsulate the information
-//! provides by the x86_64 crate and call the `on_interrupt` method on the CPU
-//! object.
+//! The exceptions enter through assembly stubs saving the general purpose
+//! registers, which the `x86-interrupt` calling convention doesn't expose.
+//! The CPU state is captured in an `InterruptionDetails`
+//! and delivered to the exception handler registered through the CPU object
+//! (see `kernel::exceptions`), which decides whether the execution resumes.
+//! Hardware interrupts are dispatched to the IRQ handlers registered the
+//! same way.
 //!
 //! The main goal of this approach is to keep all the unsafe/low level/idt specific
 //! code isolated in this module and having a more high level abstraction,
 //! the CPU object, dealing with it.
+use core::mem;
+
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{
+    Entry, HandlerFunc, InterruptDescriptorTable, InterruptStackFrameValue, PageFaultErrorCode,
+};
 
+use crate::kernel::exceptions::{self, ExceptionAction};
 use crate::kprintln;
 
+use super::apic::{spurious_interrupt_handler, SPURIOUS_VECTOR};
+use super::cpu::{InterruptionDetails, InterruptionType, Registers};
 use super::gdt::DOUBLE_FAULT_IST_INDEX;
-use super::pic_interrupts::{keyboard_interrupt_handler, timer_interrupt_handler, InterruptIndex};
+use super::memory::{handle_page_fault, is_stack_guard};
+use super::pic_interrupts::{IRQ_BASE_VECTOR, IRQ_ENTRY_POINTS};
+
+/// Sets an IDT entry to an exception entry stub. The stubs aren't
+/// `x86-interrupt` functions, but the IDT only uses their address.
+macro_rules! set_stub {
+    ($entry:expr, $stub:ident) => {
+        $entry.set_handler_fn(unsafe { mem::transmute($stub as unsafe extern "C" fn()) })
+    };
+}
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         // configure interruption handlers
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
-        }
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        set_stub!(idt.divide_error, divide_error_stub);
+        set_stub!(idt.debug, debug_stub);
+        set_stub!(idt.non_maskable_interrupt, non_maskable_interrupt_stub);
+        set_stub!(idt.breakpoint, breakpoint_stub);
+        set_stub!(idt.overflow, overflow_stub);
+        set_stub!(idt.bound_range_exceeded, bound_range_exceeded_stub);
+        set_stub!(idt.invalid_opcode, invalid_opcode_stub);
+        set_stub!(idt.device_not_available, device_not_available_stub);
+        let double_fault = set_stub!(idt.double_fault, double_fault_stub);
+        unsafe { double_fault.set_stack_index(DOUBLE_FAULT_IST_INDEX) };
+        set_stub!(idt.invalid_tss, invalid_tss_stub);
+        set_stub!(idt.segment_not_present, segment_not_present_stub);
+        set_stub!(idt.stack_segment_fault, stack_segment_fault_stub);
+        set_stub!(idt.general_protection_fault, general_protection_fault_stub);
+        set_stub!(idt.page_fault, page_fault_stub);
+        set_stub!(idt.x87_floating_point, x87_floating_point_stub);
+        set_stub!(idt.alignment_check, alignment_check_stub);
+        set_stub!(idt.machine_check, machine_check_stub);
+        set_stub!(idt.simd_floating_point, simd_floating_point_stub);
+        set_stub!(idt.virtualization, virtualization_stub);
+        set_stub!(idt.security_exception, security_exception_stub);
+        let entries = entries_by_vector(&mut idt);
+        set_stub!(entries[21], control_protection_stub);
+        set_stub!(entries[28], hypervisor_injection_stub);
+        set_stub!(entries[29], vmm_communication_stub);
 
-        // interruption handlers for PIC interruptions
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
+        // hardware interruptions are dispatched to the registered handlers
+        for (line, &entry_point) in IRQ_ENTRY_POINTS.iter().enumerate() {
+            idt[IRQ_BASE_VECTOR as usize + line].set_handler_fn(entry_point);
+        }
+        idt[SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
 
         idt
     };
 }
 
-/// Load the IDT table to the processor and stores the CPU instance
-/// reference to callback on interruptions.
+/// Load the IDT table to the processor.
 /// This method should be called by the CPU object itself when
 /// initializing.
 pub fn init_idt() {
@@ -53,43 +89,204 @@ pub fn init_idt() {
     IDT.load();
 }
 
-//# Interruption callbacks - just proxy callbacks to the CPU
+//# Interruption callbacks - just proxy callbacks to the kernel handlers
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    error_code: u64,
-) -> ! {
-    unsafe {
-        // let detail = InterruptionDetails {
-        //     error_code: Some(error_code),
-        // };
-        // CPU_INSTANCE.unwrap().on_interruption(
-        //     InterruptionType::DoubleFault,
-        //     stack_frame,
-        //     Some(detail),
-        // );
-        // TODO what shall we do here?
-        panic!("Kernel panic: Double fault")
+/// Delivers an exception to the kernel, panicking unless it can be resumed.
+/// The execution resumes with the state left by the exception handler.
+fn handle_exception(frame: &mut ExceptionFrame, kind: InterruptionType) {
+    let mut details = frame.details(kind);
+    if exceptions::dispatch(&mut details) == ExceptionAction::Panic {
+        crash(details);
     }
+    frame.update(&details);
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    unsafe {
-        // CPU_INSTANCE
-        //     .unwrap()
-        //     .on_interruption(InterruptionType::Breakpoint, stack_frame, None);
+/// Every entry of the IDT, indexed by vector. The entries of the exceptions
+/// added by recent CPUs (#CP, #HV, #VC) are reserved in the x86_64 crate,
+/// which doesn't expose them.
+fn entries_by_vector(idt: &mut InterruptDescriptorTable) -> &mut [Entry<HandlerFunc>; 256] {
+    // the table is `repr(C)`, made of its 256 entries in vector order
+    unsafe { &mut *(idt as *mut InterruptDescriptorTable).cast() }
+}
+
+/// Prints the crash report of an exception the kernel can't recover from
+/// and panics.
+fn crash(details: InterruptionDetails) -> ! {
+    kprintln!("{}", details);
+    panic!("Kernel panic: {:?}", details.kind)
+}
+
+extern "C" {
+    fn divide_error_stub();
+    fn debug_stub();
+    fn non_maskable_interrupt_stub();
+    fn breakpoint_stub();
+    fn overflow_stub();
+    fn bound_range_exceeded_stub();
+    fn invalid_opcode_stub();
+    fn device_not_available_stub();
+    fn double_fault_stub();
+    fn invalid_tss_stub();
+    fn segment_not_present_stub();
+    fn stack_segment_fault_stub();
+    fn general_protection_fault_stub();
+    fn page_fault_stub();
+    fn x87_floating_point_stub();
+    fn alignment_check_stub();
+    fn machine_check_stub();
+    fn simd_floating_point_stub();
+    fn virtualization_stub();
+    fn control_protection_stub();
+    fn hypervisor_injection_stub();
+    fn vmm_communication_stub();
+    fn security_exception_stub();
+}
+
+// Each stub pushes the vector (and a null error code when the CPU doesn't
+// push one), so every exception leaves the same frame on the stack. The
+// common entry saves the general purpose registers after them and calls
+// `exception_entry` with the frame, restoring the registers once it returns.
+// The CPU aligns the stack on 16 bytes before pushing its frame, and the
+// whole frame is 22 words long, so the stack is aligned at the call.
+global_asm!(
+    r#"
+.macro exception_stub name, vector
+.global \name
+\name:
+    pushq $0
+    pushq $\vector
+    jmp exception_common
+.endm
+
+.macro exception_stub_with_error_code name, vector
+.global \name
+\name:
+    pushq $\vector
+    jmp exception_common
+.endm
+
+exception_stub divide_error_stub, 0
+exception_stub debug_stub, 1
+exception_stub non_maskable_interrupt_stub, 2
+exception_stub breakpoint_stub, 3
+exception_stub overflow_stub, 4
+exception_stub bound_range_exceeded_stub, 5
+exception_stub invalid_opcode_stub, 6
+exception_stub device_not_available_stub, 7
+exception_stub_with_error_code double_fault_stub, 8
+exception_stub_with_error_code invalid_tss_stub, 10
+exception_stub_with_error_code segment_not_present_stub, 11
+exception_stub_with_error_code stack_segment_fault_stub, 12
+exception_stub_with_error_code general_protection_fault_stub, 13
+exception_stub_with_error_code page_fault_stub, 14
+exception_stub x87_floating_point_stub, 16
+exception_stub_with_error_code alignment_check_stub, 17
+exception_stub machine_check_stub, 18
+exception_stub simd_floating_point_stub, 19
+exception_stub virtualization_stub, 20
+exception_stub_with_error_code control_protection_stub, 21
+exception_stub hypervisor_injection_stub, 28
+exception_stub_with_error_code vmm_communication_stub, 29
+exception_stub_with_error_code security_exception_stub, 30
+
+exception_common:
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    cld
+    movq %rsp, %rdi
+    call exception_entry
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+    addq $16, %rsp
+    iretq
+"#
+);
+
+/// Stack of an exception, as left by the entry stubs
+#[repr(C)]
+struct ExceptionFrame {
+    registers: Registers,
+    vector: u64,
+    /// `0` for the exceptions without error code
+    error_code: u64,
+    stack_frame: InterruptStackFrameValue,
+}
+
+impl ExceptionFrame {
+    fn details(&self, kind: InterruptionType) -> InterruptionDetails {
+        let error_code = if kind.has_error_code() {
+            Some(self.error_code)
+        } else {
+            None
+        };
+        InterruptionDetails::capture(kind, &self.stack_frame, error_code, self.registers)
+    }
+
+    /// Applies the changes made by the exception handler, which are
+    /// restored by the entry stub when it returns
+    fn update(&mut self, details: &InterruptionDetails) {
+        self.stack_frame.instruction_pointer = details.instruction_pointer;
+        self.stack_frame.stack_pointer = details.stack_pointer;
+        self.registers = details.registers;
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    kprintln!("EXCEPTION: PAGE FAULT");
-    kprintln!("Accessed Address: {:?}", Cr2::read());
-    kprintln!("Error Code: {:?}", error_code);
-    kprintln!("{:#?}", stack_frame);
-    panic!("Page fault") // TODO what to do here?
+/// Called by the entry stubs with the exception frame
+#[no_mangle]
+extern "C" fn exception_entry(frame: &mut ExceptionFrame) {
+    let kind = InterruptionType::from_vector(frame.vector as u8)
+        .expect("Exception entry called with an unknown vector");
+    match kind {
+        InterruptionType::DoubleFault => {
+            // a page fault on a stack guard page can't be delivered on the
+            // overflowed stack, so it ends up here
+            if is_stack_guard(Cr2::read()) {
+                kprintln!("{:#?}", frame.stack_frame);
+                panic!("Kernel panic: Kernel stack overflow at {:?}", Cr2::read());
+            }
+            crash(frame.details(kind))
+        }
+        InterruptionType::MachineCheck => crash(frame.details(kind)),
+        InterruptionType::PageFault => {
+            // lazily mapped memory is allocated on first access
+            let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            if handle_page_fault(Cr2::read(), error_code) {
+                return;
+            }
+
+            if is_stack_guard(Cr2::read()) {
+                kprintln!("{:#?}", frame.stack_frame);
+                panic!("Kernel stack overflow at {:?}", Cr2::read());
+            }
+
+            handle_exception(frame, kind)
+        }
+        _ => handle_exception(frame, kind),
+    }
 }
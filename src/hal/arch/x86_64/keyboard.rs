@@ -0,0 +1,25 @@
+//! PS/2 keyboard, behind the 8042 controller.
+//! The controller holds a single scancode, raising the keyboard IRQ line when
+//! it gets one, and only delivers the next one once it's read: scancodes are
+//! read in task context, woken by an `InterruptStream` on the line.
+use x86_64::instructions::port::Port;
+
+use super::pic_interrupts::{InterruptIndex, IRQ_BASE_VECTOR};
+
+/// IRQ line of the keyboard
+pub const KEYBOARD_LINE: u8 = InterruptIndex::Keyboard as u8 - IRQ_BASE_VECTOR;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+/// Status bit set while the controller holds a byte
+const OUTPUT_FULL: u8 = 1 << 0;
+
+/// Reads the scancode held by the controller, if any
+pub fn read_scancode() -> Option<u8> {
+    unsafe {
+        if Port::<u8>::new(STATUS_PORT).read() & OUTPUT_FULL == 0 {
+            return None;
+        }
+        Some(Port::<u8>::new(DATA_PORT).read())
+    }
+}
@@ -1,49 +1,125 @@
 //! This module contains the low level x86_64 memory manager using pagination.
 //! It assumes the full physical memory is mapped by the boot loader with a offset.
+//!
+//! The resources handed out by `Memory` (kernel stacks, DMA buffers, MMIO
+//! regions, address spaces) don't keep a reference to it, so they can't give
+//! their frames back when dropped: each one must be passed to its release
+//! method (`free_stack`, `free_dma`, `unmap_mmio`, `AddressSpace::destroy`).
+//!
+//! The kernel heap grows through `Memory`, so code holding the `Memory` lock
+//! must not allocate from the heap (see `kernel::heap` for the lock order),
+//! which is why its bookkeeping is kept in fixed size tables. Nor can it
+//! access lazily mapped memory, as the page fault handler needs the lock.
 
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ptr::NonNull;
+
+use bootloader::bootinfo::MemoryMap;
+use conquer_once::spin::OnceCell;
 use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, UnmapError};
+use x86_64::structures::paging::page::PageRangeInclusive;
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, MapperAllSizes, OffsetPageTable, Page, PageTableFlags, PhysFrame,
-    Size4KiB,
+    FrameAllocator, FrameDeallocator, Mapper, MapperAllSizes, OffsetPageTable, Page,
+    PageTableFlags, PhysFrame, Size4KiB,
 };
 use x86_64::{structures::paging::PageTable, PhysAddr, VirtAddr};
 
+use crate::commons::Locked;
 use crate::kprintln;
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+use super::address_space::{AddressSpace, USER_SPACE_END, USER_SPACE_START};
+use super::dma::{DmaBuffer, DmaError, DmaZone};
+use super::frame_allocator::{frames_for, PhysicalFrameAllocator};
+use super::memory_map::MemoryMapSummary;
+use super::mmio::{mmio_flags, MmioError, MmioRegion, MmioWindow};
+use super::page_walker::{Mapping, PageTableWalker};
+use super::protection::kernel_data_flags;
+use super::stack::{in_stacks_window, KernelStack, StackError, StackSlots};
+use super::vma::{Vma, VmaError, VmaKind, VmaList};
+
+/// First address of the kernel window, holding the mappings made at runtime:
+/// the heap, the kernel stacks, MMIO and reserved areas
+pub const KERNEL_WINDOW_START: u64 = 0x_4400_0000_0000;
+/// End (exclusive) of the kernel window
+pub const KERNEL_WINDOW_END: u64 = 0x_4480_0000_0000;
+/// The kernel window spans a single level 4 entry
+const KERNEL_WINDOW_L4_ENTRY: usize = (KERNEL_WINDOW_START >> 39) as usize;
+
+#[derive(Debug)]
+pub enum ReserveError {
+    /// The range isn't inside the kernel window
+    OutsideKernelWindow,
+    /// The area can't be added to the kernel areas
+    AreaRejected(VmaError),
 }
 
-impl BootInfoFrameAllocator {
-    fn new(memory_map: &'static MemoryMap) -> Self {
-        Self {
-            memory_map,
-            next: 0,
-        }
+impl From<VmaError> for ReserveError {
+    fn from(err: VmaError) -> Self {
+        ReserveError::AreaRejected(err)
     }
+}
+
+static MEMORY: OnceCell<Locked<Memory>> = OnceCell::uninit();
+
+/// Initializes the global `Memory` instance, used by the subsystems that need
+/// to map memory at runtime, such as the kernel heap.
+pub fn init_memory(memory_offset: u64, mem_map: &'static MemoryMap) -> &'static Locked<Memory> {
+    MEMORY
+        .try_init_once(|| Locked::new(Memory::new(memory_offset, mem_map)))
+        .expect("Memory already initialized");
+    memory()
+}
+
+/// Returns the global `Memory` instance.
+/// Code holding the lock must not allocate from the kernel heap, as the heap
+/// itself uses `Memory` to grow, nor access lazily mapped memory, as page
+/// faults need the lock to map it.
+pub fn memory() -> &'static Locked<Memory> {
+    MEMORY.try_get().expect("Memory not initialized")
+}
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+/// Returns the summary of the physical memory map kept by the global `Memory`.
+pub fn memory_map() -> MemoryMapSummary {
+    memory().lock().memory_map()
+}
+
+/// Tries to resolve a page fault at the given address using the global
+/// `Memory`. Returns `false` if the fault can't be handled, either because
+/// the access is invalid or because `Memory` isn't initialized.
+///
+/// Resolving the fault needs the `Memory` lock, so lazily mapped memory must
+/// never be accessed while holding it: a fault with the lock taken panics,
+/// unless it's a kernel stack overflow, reported by the caller.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let memory = match MEMORY.try_get() {
+        Ok(memory) => memory,
+        Err(_) => return false,
+    };
+    match memory.try_lock() {
+        Some(mut memory) => memory.handle_page_fault(addr, error_code),
+        None if in_stacks_window(addr) => false,
+        None => panic!(
+            "Page fault at {:?} while holding the Memory lock: \
+             lazily mapped memory can't be accessed by Memory operations",
+            addr
+        ),
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+/// Whether the given address is in the guard area of a kernel stack, meaning
+/// a fault there is a stack overflow. If `Memory` is locked (the overflow may
+/// have happened while it was in use) any address in the kernel stacks window
+/// is reported as a guard page.
+pub fn is_stack_guard(addr: VirtAddr) -> bool {
+    if !in_stacks_window(addr) {
+        return false;
     }
+    MEMORY
+        .try_get()
+        .ok()
+        .and_then(|memory| memory.try_lock())
+        .map_or(true, |memory| memory.is_stack_guard(addr))
 }
 
 /// X86_64 memory - hendrix maps the entire physical memory as virtual,
@@ -53,9 +129,25 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 pub struct Memory {
     physical_memory_offset: PhysAddr,
     mapper: OffsetPageTable<'static>,
-    frame_allocator: BootInfoFrameAllocator,
+    frame_allocator: PhysicalFrameAllocator,
+    /// Frame of the level 4 table created by the boot loader, used by the kernel
+    kernel_l4_frame: PhysFrame,
+    /// Lazily mapped areas of the kernel space
+    kernel_vmas: VmaList,
+    /// Process address space currently loaded, if any
+    active_space: Option<NonNull<AddressSpace>>,
+    /// Slots of the kernel stacks window
+    stacks: StackSlots,
+    /// Virtual space used to map device memory
+    mmio: MmioWindow,
+    /// Physical memory map given by the boot loader
+    memory_map: MemoryMapSummary,
 }
 
+// `active_space` is only dereferenced while the address space is active,
+// which `AddressSpace::activate` callers must guarantee.
+unsafe impl Send for Memory {}
+
 impl Memory {
     /// Initializing the `Memory` struct informing what is the offset for the
     /// physical memory mapping.
@@ -63,12 +155,285 @@ impl Memory {
         let (level_4_table_frame, _) = Cr3::read();
         let virt = VirtAddr::new(memory_offset + level_4_table_frame.start_address().as_u64());
         let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
-        unsafe {
-            return Self {
+        let mut memory = unsafe {
+            Self {
                 physical_memory_offset: PhysAddr::new(memory_offset),
                 mapper: OffsetPageTable::new(&mut *page_table_ptr, VirtAddr::new(memory_offset)),
-                frame_allocator: BootInfoFrameAllocator::new(mem_map),
+                frame_allocator: PhysicalFrameAllocator::new(mem_map, VirtAddr::new(memory_offset)),
+                kernel_l4_frame: level_4_table_frame,
+                kernel_vmas: VmaList::new(),
+                active_space: None,
+                stacks: StackSlots::new(),
+                mmio: MmioWindow::new(),
+                memory_map: MemoryMapSummary::new(mem_map),
+            }
+        };
+        memory.allocate_kernel_window();
+        memory
+    }
+
+    /// Creates the level 4 entry of the kernel window, so it's present before
+    /// any address space copies the kernel entries.
+    fn allocate_kernel_window(&mut self) {
+        let l4_table = unsafe { self.page_table(self.kernel_l4_frame) };
+        let entry = &mut l4_table[KERNEL_WINDOW_L4_ENTRY];
+        if !entry.is_unused() {
+            return;
+        }
+        let frame = self
+            .frame_allocator
+            .allocate_frame()
+            .expect("Unable to allocate the kernel window table");
+        self.zero_frame(frame);
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+
+    /// Returns the physical frame allocator, which can be used to query
+    /// the free/used frame counts.
+    pub fn frame_allocator(&mut self) -> &mut PhysicalFrameAllocator {
+        &mut self.frame_allocator
+    }
+
+    /// Summary of the physical memory map given by the boot loader
+    pub fn memory_map(&self) -> MemoryMapSummary {
+        self.memory_map
+    }
+
+    /// Virtual address where the physical memory is mapped
+    pub fn physical_memory_offset(&self) -> VirtAddr {
+        VirtAddr::new(self.physical_memory_offset.as_u64())
+    }
+
+    /// Frame holding the kernel level 4 page table
+    pub fn kernel_l4_frame(&self) -> PhysFrame {
+        self.kernel_l4_frame
+    }
+
+    /// Switches back to the kernel page table, disabling any process
+    /// address space previously activated.
+    pub fn activate_kernel_space(&mut self) {
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(self.kernel_l4_frame, flags) }
+        self.active_space = None;
+    }
+
+    /// Records the process address space loaded in the CPU, whose areas
+    /// are used to handle page faults in the user space window.
+    pub(crate) fn set_active_space(&mut self, space: Option<NonNull<AddressSpace>>) {
+        self.active_space = space;
+    }
+
+    /// Reserves a range of the kernel window to be mapped on demand: each page
+    /// is backed by a zeroed frame when first accessed. As the kernel window
+    /// is shared, the pages are visible in every address space.
+    pub fn reserve(
+        &mut self,
+        start_address: VirtAddr,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), ReserveError> {
+        let start = start_address.as_u64();
+        match start.checked_add(size as u64) {
+            Some(end) if start >= KERNEL_WINDOW_START && end <= KERNEL_WINDOW_END && size > 0 => {}
+            _ => return Err(ReserveError::OutsideKernelWindow),
+        }
+        self.kernel_vmas
+            .add(Vma::new(start_address, size, flags, VmaKind::Anonymous))?;
+        Ok(())
+    }
+
+    /// Allocates a kernel stack of the given size (rounded up to whole pages),
+    /// with unmapped guard pages below it.
+    pub fn allocate_stack(&mut self, size: usize) -> Result<KernelStack, StackError> {
+        let stack = self.stacks.reserve(size)?;
+        let flags = kernel_data_flags();
+        if self
+            .alloc_frames(stack.bottom(), stack.size(), flags)
+            .is_err()
+        {
+            unsafe { self.free_stack(stack) };
+            return Err(StackError::FrameAllocationFailed);
+        }
+        Ok(stack)
+    }
+
+    /// Releases a kernel stack and its frames.
+    ///
+    /// Unsafe because the stack must not be in use.
+    pub unsafe fn free_stack(&mut self, stack: KernelStack) {
+        self.free_frames(stack.bottom(), stack.size())
+            .expect("Unable to unmap kernel stack");
+        self.stacks.release(&stack);
+    }
+
+    /// Whether the given address is in the guard area of a kernel stack
+    pub fn is_stack_guard(&self, addr: VirtAddr) -> bool {
+        self.stacks.is_guard(addr)
+    }
+
+    /// Whether the given address is mapped in the kernel page table
+    pub fn is_mapped(&self, addr: VirtAddr) -> bool {
+        self.translate(addr).is_some()
+    }
+
+    /// Translates a kernel virtual address to its physical address
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper.translate_addr(addr)
+    }
+
+    /// Allocates a zeroed, physically contiguous buffer of at least `size`
+    /// bytes (rounded up to whole frames) inside the given zone, for devices
+    /// doing DMA.
+    pub fn allocate_dma(&mut self, size: usize, zone: DmaZone) -> Result<DmaBuffer, DmaError> {
+        if size == 0 {
+            return Err(DmaError::InvalidSize);
+        }
+        let count = frames_for(size);
+        let frames = match zone.limit() {
+            Some(limit) => self.frame_allocator.allocate_contiguous_below(count, limit),
+            None => self.frame_allocator.allocate_contiguous(count),
+        }
+        .ok_or(DmaError::NoContiguousMemory)?;
+        for frame in frames {
+            self.zero_frame(frame);
+        }
+        let virt = self.physical_memory_offset() + frames.start.start_address().as_u64();
+        Ok(DmaBuffer::new(frames, virt))
+    }
+
+    /// Releases a buffer allocated by `allocate_dma`.
+    ///
+    /// Unsafe because the buffer must no longer be used, neither by the
+    /// kernel nor by any device.
+    pub unsafe fn free_dma(&mut self, buffer: DmaBuffer) {
+        self.frame_allocator.deallocate_contiguous(buffer.frames());
+    }
+
+    /// Maps `len` bytes of device memory starting at the physical address
+    /// `phys` into the MMIO window, with caching disabled.
+    /// The frames aren't taken from the frame allocator, so this must only
+    /// be used for device memory (or memory owned by the caller).
+    pub fn map_mmio(&mut self, phys: PhysAddr, len: usize) -> Result<MmioRegion, MmioError> {
+        let region = self.mmio.reserve(phys, len)?;
+        let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+        let pages = page_range(region.mapping_start(), region.mapping_size());
+        for (index, page) in pages.enumerate() {
+            let frame = first_frame + index as u64;
+            let mapped = unsafe {
+                self.mapper
+                    .map_to(page, frame, mmio_flags(), &mut self.frame_allocator)
+            };
+            match mapped {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    self.unmap_mmio(region);
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(region)
+    }
+
+    /// Unmaps a region mapped by `map_mmio`
+    pub fn unmap_mmio(&mut self, region: MmioRegion) {
+        self.unmap_range(region.mapping_start(), region.mapping_size())
+            .expect("Unable to unmap MMIO region");
+        self.mmio.release(&region);
+    }
+
+    /// Handles a page fault, mapping a zeroed frame if the address belongs
+    /// to an anonymous area and the access is allowed, or duplicating the
+    /// page on writes to copy-on-write pages of the active address space.
+    /// Returns `false` for invalid accesses.
+    pub fn handle_page_fault(&mut self, addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+        let in_user_space = addr.as_u64() >= USER_SPACE_START && addr.as_u64() < USER_SPACE_END;
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            // the page is present, so it's not a missing lazy mapping,
+            // but it may be a write to a copy-on-write page
+            if !in_user_space || !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                return false;
+            }
+            return match self.active_space {
+                Some(space) => unsafe { space.as_ref() }.handle_cow_fault(self, addr),
+                None => false,
+            };
+        }
+
+        let (vma, mut mapper) = if in_user_space {
+            let space = match self.active_space {
+                Some(space) => unsafe { space.as_ref() },
+                None => return false,
             };
+            match space.vmas().find(addr) {
+                Some(vma) => (*vma, space.mapper(self)),
+                None => return false,
+            }
+        } else {
+            match self.kernel_vmas.find(addr) {
+                Some(vma) => (*vma, unsafe { self.kernel_mapper() }),
+                None => return false,
+            }
+        };
+        if !vma.allows(error_code) {
+            return false;
+        }
+
+        let frame = match self.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        self.zero_frame(frame);
+        let page = Page::containing_address(addr);
+        match unsafe { mapper.map_to(page, frame, vma.flags(), &mut self.frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => {
+                unsafe { self.frame_allocator.deallocate_frame(frame) };
+                false
+            }
+        }
+    }
+
+    /// Returns a new mapper for the kernel page table.
+    ///
+    /// Unsafe because it aliases `self.mapper`, so it must not be used
+    /// together with it.
+    unsafe fn kernel_mapper(&self) -> OffsetPageTable<'static> {
+        OffsetPageTable::new(
+            self.page_table(self.kernel_l4_frame),
+            self.physical_memory_offset(),
+        )
+    }
+
+    /// Returns the page table stored in the given frame.
+    ///
+    /// Unsafe because the frame must hold a page table and the caller must
+    /// avoid aliasing `&mut` references to the same table.
+    pub unsafe fn page_table(&self, frame: PhysFrame) -> &'static mut PageTable {
+        let virt = self.translate_physical_to_virtual(frame.start_address().as_u64());
+        &mut *virt.as_mut_ptr()
+    }
+
+    /// Fills the given frame with zeros
+    pub fn zero_frame(&self, frame: PhysFrame) {
+        let virt = self.translate_physical_to_virtual(frame.start_address().as_u64());
+        unsafe {
+            core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, frame.size() as usize);
+        }
+    }
+
+    /// Copies the content of a frame to another one
+    pub fn copy_frame(&self, from: PhysFrame, to: PhysFrame) {
+        let src = self.translate_physical_to_virtual(from.start_address().as_u64());
+        let dst = self.translate_physical_to_virtual(to.start_address().as_u64());
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                src.as_ptr::<u8>(),
+                dst.as_mut_ptr::<u8>(),
+                from.size() as usize,
+            );
         }
     }
 
@@ -78,14 +443,7 @@ impl Memory {
         size: usize,
         flags: PageTableFlags,
     ) -> Result<(), MapToError<Size4KiB>> {
-        let page_range = {
-            let end_address = start_address + size - 1u64;
-            let first_page = Page::containing_address(start_address);
-            let last_page = Page::containing_address(end_address);
-            Page::range_inclusive(first_page, last_page)
-        };
-
-        for page in page_range {
+        for page in page_range(start_address, size) {
             let frame = self
                 .frame_allocator
                 .allocate_frame()
@@ -101,6 +459,55 @@ impl Memory {
         Ok(())
     }
 
+    /// Unmaps the pages in the given range and returns their frames to the
+    /// frame allocator. Pages in the range that aren't mapped are ignored.
+    ///
+    /// Unsafe because the caller must guarantee the memory is no longer in use
+    /// and that the frames were allocated by this `Memory` (see `alloc_frames`).
+    pub unsafe fn free_frames(
+        &mut self,
+        start_address: VirtAddr,
+        size: usize,
+    ) -> Result<(), UnmapError> {
+        let frame_allocator = &mut self.frame_allocator;
+        unmap_pages(&mut self.mapper, start_address, size, |frame| {
+            frame_allocator.deallocate_frame(frame)
+        })
+    }
+
+    /// Unmaps the pages in the given range, without releasing their frames.
+    /// Returns the number of pages unmapped; pages that aren't mapped are ignored.
+    /// This is meant for mappings not backed by the frame allocator, such as
+    /// device memory.
+    pub fn unmap_range(
+        &mut self,
+        start_address: VirtAddr,
+        size: usize,
+    ) -> Result<usize, UnmapError> {
+        let mut unmapped = 0;
+        unmap_pages(&mut self.mapper, start_address, size, |_| unmapped += 1)?;
+        Ok(unmapped)
+    }
+
+    /// Changes the flags of every page in the given range, flushing the TLB
+    /// entry for each page.
+    /// It can be used to turn a mapping read-only (removing `WRITABLE`),
+    /// non executable (adding `NO_EXECUTE`) or user accessible
+    /// (adding `USER_ACCESSIBLE`). All pages in the range must be mapped.
+    pub fn protect_range(
+        &mut self,
+        start_address: VirtAddr,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        for page in page_range(start_address, size) {
+            unsafe {
+                self.mapper.update_flags(page, flags)?.flush();
+            }
+        }
+        Ok(())
+    }
+
     // TODO do i need any of the functions below?
 
     /// Translates a physical address to its virtual address. The translation
@@ -109,56 +516,156 @@ impl Memory {
         return VirtAddr::new(physical_address + &self.physical_memory_offset.as_u64());
     }
 
-    pub fn print_l4_table(&self) {
-        let l4_table = unsafe { self.get_active_level_4_table() };
+    /// Returns a walker over every mapping of the active page table
+    pub fn walk(&self) -> PageTableWalker {
+        self.walk_table(Cr3::read().0)
+    }
 
-        for (i, entry) in l4_table.iter().enumerate() {
-            if !entry.is_unused() {
-                kprintln!("L4 Entry {}: {:?}", i, entry);
-                // get the physical address from the entry and convert it
-                let phys = entry.frame().unwrap().start_address();
-                let virt = self.translate_physical_to_virtual(phys.as_u64());
-                let l3_table: &PageTable = unsafe { &*virt.as_mut_ptr() };
+    /// Returns a walker over every mapping of the given level 4 table, such
+    /// as the one of an `AddressSpace`
+    pub fn walk_table(&self, l4_frame: PhysFrame) -> PageTableWalker {
+        unsafe { PageTableWalker::new(l4_frame, self.physical_memory_offset()) }
+    }
 
-                // print non-empty entries of the level 3 table
-                for (i, entry) in l3_table.iter().enumerate() {
-                    if !entry.is_unused() {
-                        kprintln!("  L3 Entry {}: {:?}", i, entry);
-                    }
+    /// Prints the mappings of the active page table, coalescing contiguous
+    /// pages with the same flags.
+    pub fn dump_page_table(&self) {
+        let mut pending: Option<Mapping> = None;
+        for mapping in self.walk() {
+            pending = match pending {
+                Some(mut range) if range.is_continued_by(&mapping) => {
+                    range.size += mapping.size;
+                    Some(range)
+                }
+                Some(range) => {
+                    print_mapping(&range);
+                    Some(mapping)
                 }
+                None => Some(mapping),
+            };
+        }
+        if let Some(range) = pending {
+            print_mapping(&range);
+        }
+    }
+}
+
+/// Returns the range of pages covering `size` bytes starting at `start_address`
+pub(crate) fn page_range(start_address: VirtAddr, size: usize) -> PageRangeInclusive {
+    let end_address = start_address + size - 1u64;
+    let first_page = Page::containing_address(start_address);
+    let last_page = Page::containing_address(end_address);
+    Page::range_inclusive(first_page, last_page)
+}
+
+/// Unmaps each page in the range, calling `on_unmap` with the frame that
+/// was mapped to it.
+fn unmap_pages<F>(
+    mapper: &mut OffsetPageTable,
+    start_address: VirtAddr,
+    size: usize,
+    mut on_unmap: F,
+) -> Result<(), UnmapError>
+where
+    F: FnMut(PhysFrame),
+{
+    for page in page_range(start_address, size) {
+        match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                on_unmap(frame);
             }
+            Err(UnmapError::PageNotMapped) => {}
+            Err(err) => return Err(err),
         }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::VirtAddr;
 
-        let addresses = [
-            // the identity-mapped vga buffer page
-            0xb8000,
-            // some code page
-            0x201008,
-            // some stack page
-            0x0100_0020_1a10,
-            // virtual address mapped to physical address 0
-            self.physical_memory_offset.as_u64(),
+    use super::{memory, ReserveError, KERNEL_WINDOW_END, KERNEL_WINDOW_START};
+    use crate::hal::arch::x86_64::address_space::{AddressSpace, USER_SPACE_START};
+    use crate::hal::arch::x86_64::mmio::{MMIO_END, MMIO_START};
+    use crate::hal::arch::x86_64::protection::kernel_data_flags;
+    use crate::hal::arch::x86_64::stack::{KERNEL_STACKS_END, KERNEL_STACKS_START};
+    use crate::kernel::{HEAP_MAX_SIZE, HEAP_START_ADDRESS};
+
+    #[test_case]
+    fn test_runtime_windows_are_in_the_kernel_window() {
+        let heap_start = HEAP_START_ADDRESS as u64;
+        let windows = [
+            (heap_start, heap_start + HEAP_MAX_SIZE as u64),
+            (KERNEL_STACKS_START, KERNEL_STACKS_END),
+            (MMIO_START, MMIO_END),
         ];
-        for &address in &addresses {
-            let virt = VirtAddr::new(address);
-            let phys = self.mapper.translate_addr(virt);
-            kprintln!("{:?} -> {:?}", virt, phys);
+        for &(start, end) in windows.iter() {
+            assert!(start >= KERNEL_WINDOW_START && end <= KERNEL_WINDOW_END);
         }
     }
 
-    /// Returns a mutable reference to the active level 4 table.
-    ///
-    /// This function is unsafe because the caller must guarantee that the
-    /// complete physical memory is mapped to virtual memory at the passed
-    /// `physical_memory_offset`. Also, this function must be only called once
-    /// to avoid aliasing `&mut` references (which is undefined behavior).
-    unsafe fn get_active_level_4_table(&self) -> &'static mut PageTable {
-        let (level_4_table_frame, _) = Cr3::read();
+    #[test_case]
+    fn test_reserved_kernel_pages_are_mapped_on_access() {
+        // between the heap and the kernel stacks windows
+        let addr = VirtAddr::new(0x_4450_0000_0000);
+        memory()
+            .lock()
+            .reserve(addr, 2 * 4096, kernel_data_flags())
+            .expect("Unable to reserve kernel pages");
+        assert!(memory().lock().translate(addr).is_none());
 
-        let phys = level_4_table_frame.start_address();
-        let virt = self.translate_physical_to_virtual(phys.as_u64());
-        let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+        // the lock must be released: the page fault handler takes it
+        let ptr = addr.as_mut_ptr::<u64>();
+        unsafe {
+            ptr.write_volatile(42);
+            assert_eq!(ptr.read_volatile(), 42);
+        }
+        assert!(memory().lock().translate(addr).is_some());
+        assert!(memory().lock().translate(addr + 4096u64).is_none());
+    }
+
+    #[test_case]
+    fn test_reserved_kernel_pages_are_mapped_in_address_spaces() {
+        let addr = VirtAddr::new(0x_4450_0001_0000);
+        let mut mem = memory().lock();
+        let mut parent = AddressSpace::new(&mut mem).expect("Unable to create address space");
+        let mut child = parent
+            .clone_cow(&mut mem)
+            .expect("Unable to clone address space");
+        // reserved after the address spaces are created
+        mem.reserve(addr, 4096, kernel_data_flags())
+            .expect("Unable to reserve kernel pages");
+        unsafe { child.activate(&mut mem) };
+        drop(mem);
+
+        let ptr = addr.as_mut_ptr::<u64>();
+        unsafe {
+            ptr.write_volatile(42);
+            assert_eq!(ptr.read_volatile(), 42);
+        }
+
+        let mut mem = memory().lock();
+        assert!(child.translate(&mem, addr).is_some());
+        assert_eq!(child.translate(&mem, addr), mem.translate(addr));
+        assert_eq!(parent.translate(&mem, addr), mem.translate(addr));
+        child.destroy(&mut mem);
+        parent.destroy(&mut mem);
+    }
 
-        &mut *page_table_ptr // unsafe
+    #[test_case]
+    fn test_reserve_outside_kernel_window_fails() {
+        let mut mem = memory().lock();
+        let user_addr = VirtAddr::new(USER_SPACE_START);
+        assert!(matches!(
+            mem.reserve(user_addr, 4096, kernel_data_flags()),
+            Err(ReserveError::OutsideKernelWindow)
+        ));
+        let last_page = VirtAddr::new(KERNEL_WINDOW_END - 4096);
+        assert!(matches!(
+            mem.reserve(last_page, 2 * 4096, kernel_data_flags()),
+            Err(ReserveError::OutsideKernelWindow)
+        ));
     }
 }
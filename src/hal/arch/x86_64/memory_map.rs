@@ -0,0 +1,185 @@
+//! Summary of the physical memory map given by the boot loader.
+//! Regions are grouped in a few classes (usable, reserved, ACPI, kernel,
+//! page tables, boot loader) with the number of regions and bytes of each,
+//! so it's easy to see how much RAM the machine has and where it went.
+//! The summary is built once at boot and kept by `Memory`; kernel code gets
+//! it from `memory::memory_map`.
+use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
+
+use crate::kprintln;
+
+/// Number of region classes
+pub const REGION_CLASSES: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionClass {
+    /// Free RAM, managed by the frame allocator
+    Usable,
+    /// Memory that can't be used: reserved by the firmware, bad or unknown
+    Reserved,
+    /// ACPI tables and non volatile storage
+    Acpi,
+    /// Kernel image and stack
+    Kernel,
+    /// Page tables created by the boot loader
+    PageTables,
+    /// Boot loader code and data, boot information
+    Bootloader,
+}
+
+impl RegionClass {
+    pub const ALL: [RegionClass; REGION_CLASSES] = [
+        RegionClass::Usable,
+        RegionClass::Reserved,
+        RegionClass::Acpi,
+        RegionClass::Kernel,
+        RegionClass::PageTables,
+        RegionClass::Bootloader,
+    ];
+
+    pub fn of(region_type: MemoryRegionType) -> Self {
+        match region_type {
+            MemoryRegionType::Usable => RegionClass::Usable,
+            MemoryRegionType::AcpiReclaimable | MemoryRegionType::AcpiNvs => RegionClass::Acpi,
+            MemoryRegionType::Kernel | MemoryRegionType::KernelStack => RegionClass::Kernel,
+            MemoryRegionType::PageTable => RegionClass::PageTables,
+            MemoryRegionType::Bootloader
+            | MemoryRegionType::BootInfo
+            | MemoryRegionType::Package
+            | MemoryRegionType::InUse => RegionClass::Bootloader,
+            _ => RegionClass::Reserved,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegionClass::Usable => "usable",
+            RegionClass::Reserved => "reserved",
+            RegionClass::Acpi => "acpi",
+            RegionClass::Kernel => "kernel",
+            RegionClass::PageTables => "page tables",
+            RegionClass::Bootloader => "bootloader",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Number of regions and bytes of a class
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ClassTotals {
+    pub regions: usize,
+    pub bytes: u64,
+}
+
+/// Totals of the boot memory map, by region class
+#[derive(Clone, Copy)]
+pub struct MemoryMapSummary {
+    map: &'static MemoryMap,
+    totals: [ClassTotals; REGION_CLASSES],
+}
+
+impl MemoryMapSummary {
+    pub fn new(map: &'static MemoryMap) -> Self {
+        let mut totals = [ClassTotals::default(); REGION_CLASSES];
+        for region in map.iter() {
+            let total = &mut totals[RegionClass::of(region.region_type).index()];
+            total.regions += 1;
+            total.bytes += region_size(region);
+        }
+        Self { map, totals }
+    }
+
+    pub fn totals(&self, class: RegionClass) -> ClassTotals {
+        self.totals[class.index()]
+    }
+
+    /// RAM available to the kernel: every class but `Reserved`
+    pub fn ram_bytes(&self) -> u64 {
+        RegionClass::ALL
+            .iter()
+            .filter(|&&class| class != RegionClass::Reserved)
+            .map(|&class| self.totals(class).bytes)
+            .sum()
+    }
+
+    /// Regions of the given class, in address order
+    pub fn regions(&self, class: RegionClass) -> impl Iterator<Item = &'static MemoryRegion> {
+        self.map
+            .iter()
+            .filter(move |region| RegionClass::of(region.region_type) == class)
+    }
+
+    /// Prints a table with the totals of each class
+    pub fn print(&self) {
+        kprintln!("Memory map:");
+        kprintln!("  {:<12} {:>7} {:>12}", "class", "regions", "size");
+        for class in RegionClass::ALL.iter() {
+            let total = self.totals(*class);
+            let (size, unit) = scaled(total.bytes);
+            kprintln!(
+                "  {:<12} {:>7} {:>8} {:<3}",
+                class.name(),
+                total.regions,
+                size,
+                unit
+            );
+        }
+        let (size, unit) = scaled(self.ram_bytes());
+        kprintln!("  {:<12} {:>7} {:>8} {:<3}", "ram", "", size, unit);
+    }
+}
+
+fn region_size(region: &MemoryRegion) -> u64 {
+    region.range.end_addr() - region.range.start_addr()
+}
+
+/// Scales a number of bytes to the largest unit keeping some precision
+fn scaled(bytes: u64) -> (u64, &'static str) {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut unit = 0;
+    let mut value = bytes;
+    while value >= 1024 * 10 && unit < UNITS.len() - 1 {
+        value /= 1024;
+        unit += 1;
+    }
+    (value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hal::arch::x86_64::memory::{memory, memory_map};
+
+    use super::RegionClass;
+
+    #[test_case]
+    fn test_summary_matches_regions() {
+        let mut mem = memory().lock();
+        let summary = mem.memory_map();
+        for class in RegionClass::ALL.iter() {
+            let totals = summary.totals(*class);
+            assert_eq!(summary.regions(*class).count(), totals.regions);
+        }
+
+        let usable = summary.totals(RegionClass::Usable);
+        assert!(usable.regions > 0);
+        // the frame allocator keeps its metadata in usable memory
+        let allocatable = mem.frame_allocator().total_frames() as u64 * 4096;
+        assert!(usable.bytes >= allocatable);
+        assert!(summary.totals(RegionClass::Kernel).bytes > 0);
+        assert!(summary.ram_bytes() >= usable.bytes);
+    }
+
+    #[test_case]
+    fn test_summary_is_available_globally() {
+        let summary = memory_map();
+        let kept = memory().lock().memory_map();
+        assert_eq!(summary.ram_bytes(), kept.ram_bytes());
+        assert_eq!(
+            summary.totals(RegionClass::Usable),
+            kept.totals(RegionClass::Usable)
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Memory mapped I/O.
+//! Device registers (APIC, HPET, PCI BARs...) are mapped by `Memory::map_mmio`
+//! into a dedicated kernel window, uncached, and accessed through `MmioRegion`,
+//! which only does volatile reads and writes inside the mapped range.
+//!
+//! The window is handed out by a bump allocator: virtual space is only
+//! reused when the last mapped region is unmapped, which is fine as device
+//! mappings are long lived. Like the kernel stacks window, it's part of the
+//! kernel window (`KERNEL_WINDOW_START`), so every address space shares it.
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{PageTableFlags, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::protection::kernel_data_flags;
+
+/// First address of the MMIO window
+pub const MMIO_START: u64 = 0x_4470_0000_0000;
+/// End (exclusive) of the MMIO window
+pub const MMIO_END: u64 = 0x_4480_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Flags used to map device memory: caching disabled, write-through and
+/// never executable
+pub fn mmio_flags() -> PageTableFlags {
+    kernel_data_flags() | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+}
+
+#[derive(Debug)]
+pub enum MmioError {
+    /// The length is zero or the physical range overflows
+    InvalidRange,
+    /// There's no virtual space left in the MMIO window
+    WindowFull,
+    /// The page table update failed
+    MappingFailed(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for MmioError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        MmioError::MappingFailed(err)
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Register widths supported by `MmioRegion` accesses. Each one is read or
+/// written by a single instruction, so accesses never tear.
+/// Sealed: it can't be implemented outside of this module.
+pub trait MmioValue: sealed::Sealed + Copy {}
+
+macro_rules! mmio_values {
+    ($($ty:ty),*) => {
+        $(
+            impl sealed::Sealed for $ty {}
+            impl MmioValue for $ty {}
+        )*
+    };
+}
+
+mmio_values!(u8, u16, u32, u64);
+
+/// A range of device memory mapped in the MMIO window by `Memory::map_mmio`,
+/// until it's passed to `Memory::unmap_mmio`.
+#[derive(Debug)]
+pub struct MmioRegion {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl MmioRegion {
+    /// Virtual address of the first byte of the region
+    pub fn base(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// Physical address of the first byte of the region
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Reads the register at the given offset (in bytes) of the region.
+    /// Panics if the register is outside the region or misaligned.
+    pub fn read<T: MmioValue>(&self, offset: usize) -> T {
+        unsafe { core::ptr::read_volatile(self.register(offset)) }
+    }
+
+    /// Writes the register at the given offset (in bytes) of the region.
+    /// Panics if the register is outside the region or misaligned.
+    pub fn write<T: MmioValue>(&self, offset: usize, value: T) {
+        unsafe { core::ptr::write_volatile(self.register(offset), value) }
+    }
+
+    fn register<T: MmioValue>(&self, offset: usize) -> *mut T {
+        let size = core::mem::size_of::<T>();
+        assert!(
+            offset
+                .checked_add(size)
+                .map_or(false, |end| end <= self.len),
+            "MMIO access outside the region: offset {} size {}",
+            offset,
+            size
+        );
+        let ptr = (self.virt + offset).as_mut_ptr::<T>();
+        assert_eq!(
+            ptr as usize % core::mem::align_of::<T>(),
+            0,
+            "Misaligned MMIO access"
+        );
+        ptr
+    }
+
+    /// First page-aligned address of the mapping
+    pub(super) fn mapping_start(&self) -> VirtAddr {
+        self.virt.align_down(PAGE_SIZE)
+    }
+
+    /// Size of the mapping, rounded up to whole pages
+    pub(super) fn mapping_size(&self) -> usize {
+        mapping_size(self.phys, self.len)
+    }
+}
+
+/// Bookkeeping of the MMIO window
+pub struct MmioWindow {
+    next: u64,
+}
+
+impl MmioWindow {
+    pub const fn new() -> Self {
+        Self { next: MMIO_START }
+    }
+
+    /// Reserves virtual space to map `len` bytes starting at `phys`,
+    /// returning the region (not mapped yet).
+    pub fn reserve(&mut self, phys: PhysAddr, len: usize) -> Result<MmioRegion, MmioError> {
+        if len == 0 || phys.as_u64().checked_add(len as u64).is_none() {
+            return Err(MmioError::InvalidRange);
+        }
+        let size = mapping_size(phys, len) as u64;
+        if MMIO_END - self.next < size {
+            return Err(MmioError::WindowFull);
+        }
+        let start = self.next;
+        self.next += size;
+        Ok(MmioRegion {
+            virt: VirtAddr::new(start + phys.as_u64() % PAGE_SIZE),
+            phys,
+            len,
+        })
+    }
+
+    /// Gives back the virtual space of a region, if it's the last one reserved
+    pub fn release(&mut self, region: &MmioRegion) {
+        let start = region.mapping_start().as_u64();
+        if start + region.mapping_size() as u64 == self.next {
+            self.next = start;
+        }
+    }
+}
+
+fn mapping_size(phys: PhysAddr, len: usize) -> usize {
+    let start = phys.align_down(PAGE_SIZE).as_u64();
+    let end = (phys + len as u64).align_up(PAGE_SIZE).as_u64();
+    (end - start) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+    use crate::hal::arch::x86_64::memory::memory;
+
+    #[test_case]
+    fn test_mmio_region_accesses_physical_memory() {
+        let mut mem = memory().lock();
+        let frame = mem
+            .frame_allocator()
+            .allocate_frame()
+            .expect("Unable to allocate frame");
+        let phys = frame.start_address() + 0x10u64;
+
+        let region = mem.map_mmio(phys, 64).expect("Unable to map MMIO region");
+        assert_eq!(region.base().as_u64() % 4096, 0x10);
+        assert_eq!(mem.translate(region.base()), Some(phys));
+        region.write::<u32>(4, 0xdead_beef);
+        let direct = (mem.physical_memory_offset() + phys.as_u64() + 4u64).as_ptr::<u32>();
+        assert_eq!(unsafe { *direct }, 0xdead_beef);
+        assert_eq!(region.read::<u32>(4), 0xdead_beef);
+
+        let base = region.base();
+        mem.unmap_mmio(region);
+        assert_eq!(mem.translate(base), None);
+        unsafe { mem.frame_allocator().deallocate_frame(frame) };
+    }
+
+    #[test_case]
+    fn test_empty_mmio_region_is_rejected() {
+        let mut mem = memory().lock();
+        assert!(mem.map_mmio(x86_64::PhysAddr::new(0xb8000), 0).is_err());
+    }
+}
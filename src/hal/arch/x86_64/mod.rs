@@ -1,5 +1,20 @@
+pub mod acpi;
+pub mod address_space;
+pub mod apic;
 pub mod cpu;
+pub mod dma;
+pub mod frame_allocator;
+pub mod fw_cfg;
 mod gdt;
+pub mod interrupt_controller;
 mod interrupts;
+pub mod keyboard;
 pub mod memory;
+pub mod memory_map;
+pub mod mmio;
+pub mod page_walker;
 mod pic_interrupts;
+pub mod pit;
+pub mod protection;
+pub mod stack;
+pub mod vma;
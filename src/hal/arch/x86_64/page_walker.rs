@@ -0,0 +1,182 @@
+//! Page table walker.
+//! `PageTableWalker` iterates over every mapping of a level 4 table, across
+//! the four levels and including huge pages, yielding a `Mapping` for each
+//! page. It goes through the physical memory mapping and doesn't use the heap,
+//! so it can be used while holding the `Memory` lock.
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Number of entries in a page table
+const ENTRIES: usize = 512;
+/// Index of the level 1 tables in the walker state (level 4 is `0`)
+const LEAF_DEPTH: usize = 3;
+
+/// A page mapped by a page table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    /// Size in bytes: 4KiB, or 2MiB/1GiB for huge pages
+    pub size: u64,
+    pub phys: PhysAddr,
+    /// Effective flags: `WRITABLE` and `USER_ACCESSIBLE` only if every level
+    /// allows it, `NO_EXECUTE` if any level sets it
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    /// End address (exclusive)
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    /// Whether `next` starts right after this mapping, in both virtual and
+    /// physical memory, with the same flags; used to coalesce mappings.
+    pub fn is_continued_by(&self, next: &Mapping) -> bool {
+        next.start == self.end() && next.phys == self.phys + self.size && next.flags == self.flags
+    }
+}
+
+/// Iterator over the mappings of a level 4 table, in virtual address order
+pub struct PageTableWalker {
+    physical_memory_offset: VirtAddr,
+    tables: [&'static PageTable; 4],
+    indexes: [usize; 4],
+    /// Effective flags of the entries leading to each level
+    parent_flags: [PageTableFlags; 4],
+    depth: usize,
+}
+
+impl PageTableWalker {
+    /// Creates a walker for the level 4 table in the given frame.
+    ///
+    /// Unsafe because the frame must hold a valid level 4 table, and the
+    /// whole physical memory must be mapped at `physical_memory_offset`.
+    pub unsafe fn new(l4_frame: PhysFrame, physical_memory_offset: VirtAddr) -> Self {
+        let l4_table = table_at(l4_frame.start_address(), physical_memory_offset);
+        Self {
+            physical_memory_offset,
+            tables: [l4_table; 4],
+            indexes: [0; 4],
+            parent_flags: [parent_flags_mask(); 4],
+            depth: 0,
+        }
+    }
+
+    fn virtual_address(&self) -> VirtAddr {
+        let addr = self.indexes[..=self.depth]
+            .iter()
+            .enumerate()
+            .fold(0u64, |addr, (depth, &index)| {
+                addr | (index as u64) << (39 - 9 * depth)
+            });
+        // sign extension of bit 47
+        VirtAddr::new(((addr << 16) as i64 >> 16) as u64)
+    }
+}
+
+impl Iterator for PageTableWalker {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        loop {
+            let depth = self.depth;
+            let index = self.indexes[depth];
+            if index == ENTRIES {
+                if depth == 0 {
+                    return None;
+                }
+                self.depth -= 1;
+                self.indexes[self.depth] += 1;
+                continue;
+            }
+
+            let entry = &self.tables[depth][index];
+            let entry_flags = entry.flags();
+            if !entry_flags.contains(PageTableFlags::PRESENT) {
+                self.indexes[depth] += 1;
+                continue;
+            }
+            let flags = effective_flags(self.parent_flags[depth], entry_flags);
+
+            let is_huge = depth > 0 && entry_flags.contains(PageTableFlags::HUGE_PAGE);
+            if depth == LEAF_DEPTH || is_huge {
+                let mapping = Mapping {
+                    start: self.virtual_address(),
+                    size: 4096 << (9 * (LEAF_DEPTH - depth)),
+                    phys: entry.addr(),
+                    flags,
+                };
+                self.indexes[depth] += 1;
+                return Some(mapping);
+            }
+
+            self.depth += 1;
+            self.tables[self.depth] =
+                unsafe { table_at(entry.addr(), self.physical_memory_offset) };
+            self.indexes[self.depth] = 0;
+            self.parent_flags[self.depth] = flags;
+        }
+    }
+}
+
+/// Flags of the entries at the top of the hierarchy: nothing is restricted yet
+fn parent_flags_mask() -> PageTableFlags {
+    PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE
+}
+
+fn effective_flags(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let restricted = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut flags = entry - restricted;
+    flags |= entry & parent & restricted;
+    flags | (parent & PageTableFlags::NO_EXECUTE)
+}
+
+unsafe fn table_at(phys: PhysAddr, physical_memory_offset: VirtAddr) -> &'static PageTable {
+    &*(physical_memory_offset + phys.as_u64()).as_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::PageTableFlags;
+    use x86_64::VirtAddr;
+
+    use crate::hal::arch::x86_64::memory::memory;
+    use crate::kernel::HEAP_START_ADDRESS;
+
+    #[test_case]
+    fn test_walker_reports_heap_mapping() {
+        let mem = memory().lock();
+        let heap = VirtAddr::new(HEAP_START_ADDRESS as u64);
+        let mapping = mem
+            .walk()
+            .find(|mapping| mapping.contains(heap))
+            .expect("Heap isn't mapped");
+        assert_eq!(mapping.start, heap);
+        assert_eq!(mapping.size, 4096);
+        assert_eq!(mem.translate(heap), Some(mapping.phys));
+        assert!(mapping.flags.contains(PageTableFlags::WRITABLE));
+        assert!(!mapping.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+    }
+
+    #[test_case]
+    fn test_walker_yields_sorted_mappings_matching_translation() {
+        let mem = memory().lock();
+        let mut previous_end = VirtAddr::new(0);
+        let mut huge_pages = 0;
+        for mapping in mem.walk() {
+            assert!(mapping.start >= previous_end);
+            assert_eq!(mapping.start.as_u64() % mapping.size, 0);
+            assert_eq!(mem.translate(mapping.start), Some(mapping.phys));
+            if mapping.size > 4096 {
+                huge_pages += 1;
+            }
+            previous_end = mapping.end();
+        }
+        // the physical memory mapping uses huge pages
+        assert!(huge_pages > 0);
+    }
+}
@@ -1,10 +1,12 @@
 use pic8259_simple::ChainedPics;
 use spin::Mutex;
 use x86_64::instructions::port::Port;
-use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
 
-use crate::kernel::cpu_events::add_scancode;
-use crate::kprint;
+use crate::kernel::irq::{dispatch, IrqReturn, IRQ_LINES};
+use crate::kernel::time;
+
+use super::interrupt_controller::end_of_interrupt;
 
 /// Starting offset for a primary PIC 8259.
 pub const PIC_1_OFFSET: u8 = 32;
@@ -12,6 +14,12 @@ pub const PIC_1_OFFSET: u8 = 32;
 /// Starting offset for the secondary PIC 8259.
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// Vector of the IRQ line 0, whatever the interrupt controller in use
+pub const IRQ_BASE_VECTOR: u8 = PIC_1_OFFSET;
+
+/// Line of the primary PIC connected to the secondary one
+const CASCADE_LINE: u8 = 2;
+
 /// ChainedPics layout with a primary and secondary pic.
 ///
 ///                      ____________                          ____________
@@ -47,47 +55,88 @@ impl InterruptIndex {
     pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    /// IRQ line of the interrupt
+    pub fn line(self) -> u8 {
+        self.as_u8() - IRQ_BASE_VECTOR
+    }
 }
 
+/// Data ports of the primary and secondary PICs, used to mask their lines
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xa1;
+
 pub fn init_pic() {
     unsafe { PICS.lock().initialize() };
 }
 
-macro_rules! end_of_interruption {
-    ($arg:expr) => {
-        unsafe {
-            PICS.lock().notify_end_of_interrupt($arg);
+/// Remaps and masks every line of the PICs, used when the APIC takes over.
+pub fn disable_pic() {
+    init_pic();
+    unsafe {
+        Port::<u8>::new(PIC_1_DATA).write(0xff);
+        Port::<u8>::new(PIC_2_DATA).write(0xff);
+    }
+}
+
+/// Acknowledges an interrupt delivered by the PICs
+pub(super) fn pic_end_of_interrupt(vector: u8) {
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(vector);
+    }
+}
+
+/// Masks or unmasks a line of the PICs. Lines of the secondary PIC also
+/// need the cascade line (2) of the primary one unmasked.
+pub(super) fn set_pic_line_masked(line: u8, masked: bool) {
+    let (port, bit) = if line < 8 {
+        (PIC_1_DATA, line)
+    } else {
+        (PIC_2_DATA, line - 8)
+    };
+    unsafe {
+        let mut data = Port::<u8>::new(port);
+        let mask = data.read();
+        if masked {
+            data.write(mask | 1 << bit);
+        } else {
+            data.write(mask & !(1 << bit));
         }
+    }
+    if line >= 8 && !masked {
+        set_pic_line_masked(CASCADE_LINE, false);
+    }
+}
+
+/// Generates the IDT entry points of the IRQ lines, which dispatch the
+/// interrupt to the handlers registered for the line.
+macro_rules! irq_entry_points {
+    ($($name:ident => $line:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+                handle_irq($line);
+            }
+        )*
+
+        /// Entry points of the IRQ lines, indexed by line
+        pub(crate) const IRQ_ENTRY_POINTS: [HandlerFunc; IRQ_LINES] = [$($name),*];
     };
 }
 
-pub(crate) extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: &mut InterruptStackFrame,
-) {
-    kprint!(".");
-    end_of_interruption!(InterruptIndex::Timer.as_u8());
+irq_entry_points!(
+    irq_0 => 0, irq_1 => 1, irq_2 => 2, irq_3 => 3,
+    irq_4 => 4, irq_5 => 5, irq_6 => 6, irq_7 => 7,
+    irq_8 => 8, irq_9 => 9, irq_10 => 10, irq_11 => 11,
+    irq_12 => 12, irq_13 => 13, irq_14 => 14, irq_15 => 15,
+);
+
+fn handle_irq(line: u8) {
+    // interrupts nobody claims are acknowledged and dropped
+    dispatch(line);
+    end_of_interrupt(IRQ_BASE_VECTOR + line);
 }
 
-pub(crate) extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: &mut InterruptStackFrame,
-) {
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    add_scancode(scancode);
-    end_of_interruption!(InterruptIndex::Keyboard.as_u8());
-
-    // lazy_static! {
-    //     static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-    //         Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-    //     );
-    // }
-    // let mut keyboard = KEYBOARD.lock();
-    // if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-    //     if let Some(key) = keyboard.process_keyevent(key_event) {
-    //         match key {
-    //             DecodedKey::Unicode(character) => kprint!("{}", character),
-    //             DecodedKey::RawKey(key) => kprint!("{:?}", key),
-    //         }
-    //     }
-    // }
+pub(crate) fn timer_handler(_line: u8) -> IrqReturn {
+    time::tick();
+    IrqReturn::Handled
 }
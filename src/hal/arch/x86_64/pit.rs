@@ -0,0 +1,123 @@
+//! 8253/8254 Programmable Interval Timer (PIT) and Time Stamp Counter (TSC).
+//! The PIT channel 0 drives the timer interrupt (IRQ line 0) at a known
+//! frequency, while the channel 2, which can be polled, is used as a
+//! reference to measure the TSC frequency.
+use core::arch::x86_64::_rdtsc;
+use core::time::Duration;
+
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT input clock, in Hz
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Keyboard controller port B, controlling the channel 2 gate
+const PORT_B: u16 = 0x61;
+
+/// Command bits: channel, access mode (low byte then high byte), operating mode
+const COMMAND_CHANNEL_0: u8 = 0b00 << 6;
+const COMMAND_CHANNEL_2: u8 = 0b10 << 6;
+const COMMAND_LOW_HIGH: u8 = 0b11 << 4;
+const MODE_TERMINAL_COUNT: u8 = 0b000 << 1;
+const MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+/// Port B bits
+const GATE_2: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+const OUTPUT_2: u8 = 1 << 5;
+
+/// The TSC is measured during 1/CALIBRATION_RATE seconds (50 ms)
+const CALIBRATION_RATE: u64 = 20;
+/// Maximum number of reads of the channel 2 output during the calibration.
+/// A port read takes about a microsecond, so it's well above the 50 ms.
+const CALIBRATION_MAX_POLLS: u64 = 10_000_000;
+
+/// Programs the channel 0 to fire the timer interrupt periodically, as close
+/// as possible to the given frequency. Returns the actual period.
+pub fn set_timer_frequency(frequency: u32) -> Duration {
+    let divisor = divisor(frequency);
+    unsafe {
+        Port::<u8>::new(PIT_COMMAND)
+            .write(COMMAND_CHANNEL_0 | COMMAND_LOW_HIGH | MODE_RATE_GENERATOR);
+        let mut channel = Port::<u8>::new(PIT_CHANNEL_0);
+        channel.write(divisor as u8);
+        channel.write((divisor >> 8) as u8);
+    }
+    period(divisor)
+}
+
+/// Reads the Time Stamp Counter
+pub fn read_tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Measures the TSC frequency, in Hz, against the channel 2.
+/// Busy waits for 50 ms. Returns `None` if the channel 2 output never
+/// goes high (e.g. there's no PIT) or the TSC doesn't advance.
+pub fn calibrate_tsc() -> Option<u64> {
+    let count = PIT_FREQUENCY / CALIBRATION_RATE;
+    unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+        // stop the channel while it's programmed, keeping the speaker off
+        let value = port_b.read() & !(GATE_2 | SPEAKER);
+        port_b.write(value);
+
+        Port::<u8>::new(PIT_COMMAND)
+            .write(COMMAND_CHANNEL_2 | COMMAND_LOW_HIGH | MODE_TERMINAL_COUNT);
+        let mut channel = Port::<u8>::new(PIT_CHANNEL_2);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+
+        // the output goes high once the count reaches 0
+        port_b.write(value | GATE_2);
+        let start = read_tsc();
+        let mut polls = 0;
+        while port_b.read() & OUTPUT_2 == 0 && polls < CALIBRATION_MAX_POLLS {
+            polls += 1;
+        }
+        let end = read_tsc();
+        port_b.write(value);
+
+        if polls == CALIBRATION_MAX_POLLS || end <= start {
+            return None;
+        }
+        Some((end - start) * CALIBRATION_RATE)
+    }
+}
+
+/// Channel divisor for the given frequency.
+/// A divisor of 0 stands for 65536, the slowest rate.
+fn divisor(frequency: u32) -> u16 {
+    let divisor = PIT_FREQUENCY / frequency.max(1) as u64;
+    if divisor > u16::MAX as u64 {
+        0
+    } else {
+        divisor.max(1) as u16
+    }
+}
+
+/// Period of the channel output for the given divisor
+fn period(divisor: u16) -> Duration {
+    let divisor = if divisor == 0 { 65536 } else { divisor as u64 };
+    Duration::from_nanos(divisor * 1_000_000_000 / PIT_FREQUENCY)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use super::{divisor, period};
+
+    #[test_case]
+    fn test_divisor_and_period() {
+        assert_eq!(divisor(1000), 1193);
+        assert_eq!(period(1193), Duration::from_nanos(999_847));
+        // too slow, clamped to the slowest rate
+        assert_eq!(divisor(1), 0);
+        assert_eq!(period(0), Duration::from_nanos(54_925_401));
+        // too fast, clamped to the fastest rate
+        assert_eq!(divisor(u32::MAX), 1);
+    }
+}
@@ -0,0 +1,234 @@
+//! Kernel memory protection (W^X).
+//! `harden_kernel` runs once at boot: it enables the no-execute bit
+//! (EFER.NXE), remaps the kernel segments according to their ELF flags
+//! (.text read-only and executable, .rodata read-only, data writable, both
+//! non executable), marks every other writable kernel page (heap, stacks,
+//! physical memory mapping...) as non executable and then checks that no
+//! page is left both writable and executable.
+//!
+//! The kernel segments are found through the ELF program headers, which the
+//! linker maps with the kernel image and exposes as `__ehdr_start`.
+//! Mappings created after the hardening pass must use `kernel_data_flags`
+//! (or add `NO_EXECUTE` through `nx_flag`) to keep the invariant.
+use x86_64::instructions::tlb;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::FlagUpdateError;
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use crate::kprintln;
+
+use super::memory::Memory;
+use super::page_walker::Mapping;
+
+/// ELF program header type of loadable segments
+const PT_LOAD: u32 = 1;
+/// ELF segment flags
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+extern "C" {
+    /// ELF header of the kernel image, defined by the linker
+    static __ehdr_start: Elf64Header;
+}
+
+#[repr(C)]
+struct Elf64Header {
+    ident: [u8; 16],
+    elf_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+struct Elf64ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+#[derive(Debug)]
+pub enum ProtectionError {
+    /// The kernel image has no valid ELF header
+    InvalidKernelImage,
+    /// A kernel segment couldn't be remapped
+    SegmentRemapFailed(FlagUpdateError),
+    /// A page is still both writable and executable
+    WritableExecutable(Mapping),
+}
+
+/// Enables the no-execute page protection (EFER.NXE)
+pub fn enable_nx() {
+    unsafe { Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE) }
+}
+
+/// Whether the no-execute page protection is enabled. Setting `NO_EXECUTE`
+/// before that is a reserved bit violation, so flags must check it first.
+pub fn nx_enabled() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+/// `NO_EXECUTE` if the protection is enabled, empty otherwise
+pub fn nx_flag() -> PageTableFlags {
+    if nx_enabled() {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Flags for kernel data pages: writable and, when possible, non executable
+pub fn kernel_data_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | nx_flag()
+}
+
+/// Enforces W^X on the kernel page table. See the module documentation.
+pub fn harden_kernel(memory: &mut Memory) -> Result<(), ProtectionError> {
+    kprintln!("Enforcing W^X on kernel mappings");
+    enable_nx();
+
+    let kernel_l4_frame = memory.kernel_l4_frame();
+    set_nx_on_writable(memory, kernel_l4_frame, 4);
+    remap_kernel_segments(memory)?;
+    tlb::flush_all();
+
+    verify_wx(memory)
+}
+
+/// Checks that no page of the active page table is both writable and executable
+pub fn verify_wx(memory: &Memory) -> Result<(), ProtectionError> {
+    match memory
+        .walk()
+        .find(|mapping| is_writable_executable(mapping))
+    {
+        Some(mapping) => Err(ProtectionError::WritableExecutable(mapping)),
+        None => Ok(()),
+    }
+}
+
+fn is_writable_executable(mapping: &Mapping) -> bool {
+    mapping.flags.contains(PageTableFlags::WRITABLE)
+        && !mapping.flags.contains(PageTableFlags::NO_EXECUTE)
+}
+
+/// Adds `NO_EXECUTE` to every writable page (4KiB or huge) mapped by the
+/// given table
+fn set_nx_on_writable(memory: &Memory, table_frame: PhysFrame, level: u8) {
+    let table: &mut PageTable = unsafe { memory.page_table(table_frame) };
+    for entry in table.iter_mut() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let is_leaf = level == 1 || (level < 4 && flags.contains(PageTableFlags::HUGE_PAGE));
+        if !is_leaf {
+            set_nx_on_writable(
+                memory,
+                PhysFrame::containing_address(entry.addr()),
+                level - 1,
+            );
+        } else if flags.contains(PageTableFlags::WRITABLE) {
+            entry.set_flags(flags | PageTableFlags::NO_EXECUTE);
+        }
+    }
+}
+
+/// Remaps the loadable segments of the kernel image with the protection
+/// given by their ELF flags
+fn remap_kernel_segments(memory: &mut Memory) -> Result<(), ProtectionError> {
+    let header = unsafe { &__ehdr_start };
+    if header.ident[..4] != [0x7f, b'E', b'L', b'F']
+        || header.phentsize as usize != core::mem::size_of::<Elf64ProgramHeader>()
+    {
+        return Err(ProtectionError::InvalidKernelImage);
+    }
+
+    let first_header = VirtAddr::from_ptr(header) + header.phoff;
+    for index in 0..header.phnum as u64 {
+        let segment = unsafe {
+            &*(first_header + index * header.phentsize as u64).as_ptr::<Elf64ProgramHeader>()
+        };
+        if segment.p_type != PT_LOAD || segment.memsz == 0 {
+            continue;
+        }
+        let flags = segment_flags(segment.p_flags);
+        memory
+            .protect_range(VirtAddr::new(segment.vaddr), segment.memsz as usize, flags)
+            .map_err(ProtectionError::SegmentRemapFailed)?;
+    }
+    Ok(())
+}
+
+fn segment_flags(elf_flags: u32) -> PageTableFlags {
+    if elf_flags & PF_X != 0 {
+        // code is never writable
+        PageTableFlags::PRESENT
+    } else if elf_flags & PF_W != 0 {
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::PageTableFlags;
+    use x86_64::VirtAddr;
+
+    use crate::hal::arch::x86_64::memory::memory;
+
+    use super::verify_wx;
+
+    static READ_ONLY: u64 = 42;
+
+    fn flags_of(addr: VirtAddr) -> PageTableFlags {
+        memory()
+            .lock()
+            .walk()
+            .find(|mapping| mapping.contains(addr))
+            .expect("Address not mapped")
+            .flags
+    }
+
+    // the test entry point hardens the kernel before running the tests
+    #[test_case]
+    fn test_kernel_has_no_writable_executable_page() {
+        let mut mem = memory().lock();
+        assert!(verify_wx(&mem).is_ok());
+        // new kernel mappings keep the invariant
+        let stack = mem.allocate_stack(4096).expect("Unable to allocate stack");
+        assert!(verify_wx(&mem).is_ok());
+        unsafe { mem.free_stack(stack) };
+    }
+
+    #[test_case]
+    fn test_kernel_segments_are_protected() {
+        let code = flags_of(VirtAddr::new(flags_of as usize as u64));
+        assert!(!code.contains(PageTableFlags::WRITABLE));
+        assert!(!code.contains(PageTableFlags::NO_EXECUTE));
+
+        let rodata = flags_of(VirtAddr::from_ptr(&READ_ONLY));
+        assert!(!rodata.contains(PageTableFlags::WRITABLE));
+        assert!(rodata.contains(PageTableFlags::NO_EXECUTE));
+
+        let local = 0u64;
+        let stack = flags_of(VirtAddr::from_ptr(&local));
+        assert!(stack.contains(PageTableFlags::WRITABLE));
+        assert!(stack.contains(PageTableFlags::NO_EXECUTE));
+    }
+}
@@ -0,0 +1,168 @@
+//! Kernel stacks.
+//! Stacks are handed out from a dedicated virtual window, split in slots of
+//! `STACK_SLOT_SIZE` bytes. Each stack is mapped at the top of its slot and
+//! the pages below it are never mapped, so a stack overflow hits a guard page
+//! and faults instead of silently corrupting the memory next to it.
+//!
+//! The window is part of the kernel window (`KERNEL_WINDOW_START`), so stacks
+//! allocated after an address space is created are visible in it as well.
+//! Up to `MAX_KERNEL_STACKS` stacks can be allocated at the same time.
+use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::VirtAddr;
+
+/// First address of the kernel stacks window
+pub const KERNEL_STACKS_START: u64 = 0x_4460_0000_0000;
+/// Virtual space reserved for each stack, including its guard pages
+pub const STACK_SLOT_SIZE: usize = 64 * 1024;
+/// Maximum number of kernel stacks
+pub const MAX_KERNEL_STACKS: usize = 64;
+/// Largest stack that can be allocated, leaving at least one guard page
+pub const MAX_STACK_SIZE: usize = STACK_SLOT_SIZE - PAGE_SIZE;
+/// End (exclusive) of the kernel stacks window
+pub const KERNEL_STACKS_END: u64 =
+    KERNEL_STACKS_START + (STACK_SLOT_SIZE * MAX_KERNEL_STACKS) as u64;
+
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum StackError {
+    /// The requested size is zero or larger than `MAX_STACK_SIZE`
+    InvalidSize,
+    /// Every slot is in use
+    NoFreeSlot,
+    /// There's no free frame left to back the stack
+    FrameAllocationFailed,
+}
+
+/// A kernel stack, allocated by `Memory::allocate_stack` and released by
+/// `Memory::free_stack`.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    /// Address right after the end of the stack, to be loaded as stack pointer
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// Lowest address of the stack
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    pub fn size(&self) -> usize {
+        (self.top - self.bottom) as usize
+    }
+
+    /// The unmapped page right below the stack
+    pub fn guard_page(&self) -> Page<Size4KiB> {
+        Page::containing_address(self.bottom - 1u64)
+    }
+}
+
+/// Bookkeeping of the slots in the kernel stacks window
+pub struct StackSlots {
+    /// Size of the stack using each slot, `0` for free slots
+    sizes: [usize; MAX_KERNEL_STACKS],
+}
+
+impl StackSlots {
+    pub const fn new() -> Self {
+        Self {
+            sizes: [0; MAX_KERNEL_STACKS],
+        }
+    }
+
+    /// Reserves a free slot for a stack of the given size (rounded up to
+    /// whole pages). The stack memory still needs to be mapped.
+    pub fn reserve(&mut self, size: usize) -> Result<KernelStack, StackError> {
+        let size = (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        if size == 0 || size > MAX_STACK_SIZE {
+            return Err(StackError::InvalidSize);
+        }
+        let slot = self
+            .sizes
+            .iter()
+            .position(|&used| used == 0)
+            .ok_or(StackError::NoFreeSlot)?;
+        self.sizes[slot] = size;
+
+        let top = slot_start(slot) + STACK_SLOT_SIZE;
+        Ok(KernelStack {
+            slot,
+            bottom: top - size,
+            top,
+        })
+    }
+
+    pub fn release(&mut self, stack: &KernelStack) {
+        self.sizes[stack.slot] = 0;
+    }
+
+    /// Whether the given address is in the guard area of an allocated stack
+    pub fn is_guard(&self, addr: VirtAddr) -> bool {
+        if !in_stacks_window(addr) {
+            return false;
+        }
+        let offset = addr - VirtAddr::new(KERNEL_STACKS_START);
+        let slot = offset as usize / STACK_SLOT_SIZE;
+        let size = self.sizes[slot];
+        size != 0 && (offset as usize % STACK_SLOT_SIZE) < STACK_SLOT_SIZE - size
+    }
+}
+
+/// Whether the given address is inside the kernel stacks window
+pub fn in_stacks_window(addr: VirtAddr) -> bool {
+    addr.as_u64() >= KERNEL_STACKS_START && addr.as_u64() < KERNEL_STACKS_END
+}
+
+fn slot_start(slot: usize) -> VirtAddr {
+    VirtAddr::new(KERNEL_STACKS_START + (slot * STACK_SLOT_SIZE) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::VirtAddr;
+
+    use crate::hal::arch::x86_64::memory::memory;
+
+    use super::MAX_STACK_SIZE;
+
+    #[test_case]
+    fn test_stacks_are_separated_by_guard_pages() {
+        let mut mem = memory().lock();
+        let first = mem
+            .allocate_stack(4 * 4096)
+            .expect("Unable to allocate stack");
+        // the page tables of the window are in place after the first stack
+        let free_frames = mem.frame_allocator().free_frames();
+        let second = mem
+            .allocate_stack(4 * 4096)
+            .expect("Unable to allocate stack");
+        assert_eq!(first.size(), 4 * 4096);
+        assert!(mem.is_mapped(first.bottom()));
+        assert!(mem.is_mapped(first.top() - 1u64));
+        let guard = second.guard_page().start_address();
+        assert!(!mem.is_mapped(guard));
+        assert!(mem.is_stack_guard(guard));
+        assert!(mem.is_stack_guard(second.bottom() - 8u64));
+        assert!(!mem.is_stack_guard(second.bottom()));
+        assert!(guard >= first.top());
+
+        unsafe { mem.free_stack(second) };
+        assert_eq!(mem.frame_allocator().free_frames(), free_frames);
+        unsafe { mem.free_stack(first) };
+    }
+
+    #[test_case]
+    fn test_invalid_stack_sizes_are_rejected() {
+        let mut mem = memory().lock();
+        assert!(mem.allocate_stack(0).is_err());
+        assert!(mem.allocate_stack(MAX_STACK_SIZE + 1).is_err());
+        assert!(!mem.is_stack_guard(VirtAddr::new(0x1000)));
+    }
+}
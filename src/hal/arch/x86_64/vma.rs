@@ -0,0 +1,129 @@
+//! Virtual memory areas (VMAs).
+//! A VMA is a range of virtual memory reserved for some use, but not
+//! necessarily backed by physical memory: anonymous VMAs are mapped lazily,
+//! page by page, by the page fault handler on first touch.
+//! The lists have a fixed capacity, as they are used from the page fault
+//! handler and by `Memory`, neither of which can use the kernel heap.
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Maximum number of VMAs in an address space
+const MAX_VMAS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// Memory backed by zeroed frames, allocated on first access
+    Anonymous,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+    kind: VmaKind,
+}
+
+impl Vma {
+    /// Creates a VMA covering the pages of the given range
+    pub fn new(start: VirtAddr, size: usize, flags: PageTableFlags, kind: VmaKind) -> Self {
+        let first_page: Page<Size4KiB> = Page::containing_address(start);
+        let last_page: Page<Size4KiB> = Page::containing_address(start + size - 1u64);
+        Self {
+            start: first_page.start_address(),
+            end: last_page.start_address() + last_page.size(),
+            flags: flags | PageTableFlags::PRESENT,
+            kind,
+        }
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// End address (exclusive)
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    /// Flags used to map the pages of this area
+    pub fn flags(&self) -> PageTableFlags {
+        self.flags
+    }
+
+    pub fn kind(&self) -> VmaKind {
+        self.kind
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Whether the access described by the page fault error code is allowed
+    /// in this area.
+    pub fn allows(&self, error_code: PageFaultErrorCode) -> bool {
+        let writable = self.flags.contains(PageTableFlags::WRITABLE);
+        let user = self.flags.contains(PageTableFlags::USER_ACCESSIBLE);
+        let executable = !self.flags.contains(PageTableFlags::NO_EXECUTE);
+        (writable || !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE))
+            && (user || !error_code.contains(PageFaultErrorCode::USER_MODE))
+            && (executable || !error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH))
+    }
+
+    fn overlaps(&self, other: &Vma) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+#[derive(Debug)]
+pub enum VmaError {
+    /// The area overlaps an existing one
+    Overlap,
+    /// The list is full
+    Full,
+}
+
+/// The VMAs of an address space
+#[derive(Clone)]
+pub struct VmaList {
+    vmas: [Option<Vma>; MAX_VMAS],
+}
+
+impl VmaList {
+    pub const fn new() -> Self {
+        Self {
+            vmas: [None; MAX_VMAS],
+        }
+    }
+
+    pub fn add(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if self.iter().any(|existing| existing.overlaps(&vma)) {
+            return Err(VmaError::Overlap);
+        }
+        let slot = self
+            .vmas
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(VmaError::Full)?;
+        *slot = Some(vma);
+        Ok(())
+    }
+
+    /// Removes the area starting at the given address, returning it
+    pub fn remove(&mut self, start: VirtAddr) -> Option<Vma> {
+        self.vmas
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |vma| vma.start == start))
+            .and_then(|slot| slot.take())
+    }
+
+    /// Returns the area containing the given address
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.iter().find(|vma| vma.contains(addr))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.iter().flatten()
+    }
+}
@@ -1,4 +1,7 @@
+use crate::hal::arch::x86_64::keyboard::KEYBOARD_LINE;
 use crate::kernel::cpu_events::KeyboardStream;
+use crate::kernel::exceptions::ExceptionHandler;
+use crate::kernel::irq::{HandlerId, InterruptStream, IrqError, IrqHandler};
 
 pub trait CPUEvents {
     fn get_keyboard_stream(&self) -> KeyboardStream;
@@ -7,6 +10,35 @@ pub trait CPUEvents {
 pub trait CPU {
     fn init(&self);
     fn hlt(&self);
+    /// Enables interrupts and halts until the next one, atomically, so an
+    /// interrupt can't fire between both. Must be called with interrupts
+    /// disabled.
+    fn idle(&self);
+
+    /// Whether hardware interrupts are enabled
+    fn interrupts_enabled(&self) -> bool;
+    fn disable_interrupts(&self);
+    fn enable_interrupts(&self);
+
+    /// Registers a handler for the given IRQ line, enabling the line.
+    /// Lines can be shared by several handlers.
+    fn register_interrupt_handler(
+        &self,
+        line: u8,
+        handler: IrqHandler,
+    ) -> Result<HandlerId, IrqError>;
+
+    /// Registers a stream notified each time the given IRQ line fires,
+    /// enabling the line.
+    fn register_interrupt_stream(&self, line: u8) -> Result<InterruptStream, IrqError>;
+
+    /// Removes a handler or stream; the line is disabled once it has
+    /// no registration left.
+    fn unregister_interrupt_handler(&self, id: HandlerId) -> Result<(), IrqError>;
+
+    /// Sets the handler deciding what to do with CPU exceptions the
+    /// architecture code can't resolve by itself.
+    fn set_exception_handler(&self, handler: ExceptionHandler);
 }
 
 impl<T> CPUEvents for T
@@ -14,6 +46,9 @@ where
     T: CPU,
 {
     fn get_keyboard_stream(&self) -> KeyboardStream {
-        return KeyboardStream::new();
+        let interrupts = self
+            .register_interrupt_stream(KEYBOARD_LINE)
+            .expect("Unable to register the keyboard stream");
+        KeyboardStream::new(interrupts)
     }
 }
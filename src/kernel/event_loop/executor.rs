@@ -2,29 +2,72 @@ use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::task::Wake;
 use core::future::Future;
-use core::task::{Context, Poll, Waker};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Waker};
+use core::time::Duration;
 
 use crossbeam_queue::ArrayQueue;
 
+use crate::kernel::cpu::CPU;
+use crate::kernel::time::Instant;
+
+use super::join::{joinable, JoinHandle};
+use super::spawner::{SendFuture, Spawner, INJECTION_QUEUE_SIZE};
 use super::task::{Task, TaskId};
 
+/// Default size of the ready queue, which bounds the number of tasks woken
+/// between two polls without going through the overflow path
+pub const DEFAULT_READY_QUEUE_SIZE: usize = 100;
+
+/// Queue of the tasks ready to be polled.
+/// Wakers may run in interrupt context, where the queue can't grow: when it's
+/// full the task is only flagged as queued, and the executor looks for the
+/// flagged tasks once it has drained the queue.
+struct ReadyQueue {
+    queue: ArrayQueue<TaskId>,
+    overflowed: AtomicBool,
+}
+
+impl ReadyQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            queue: ArrayQueue::new(capacity),
+            overflowed: AtomicBool::new(false),
+        }
+    }
+
+    fn push(&self, task_id: TaskId) {
+        if self.queue.push(task_id).is_err() {
+            self.overflowed.store(true, Ordering::Release);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty() && !self.overflowed.load(Ordering::Acquire)
+    }
+}
+
 struct TaskWaker {
     task_id: TaskId,
-    ready_tasks: Arc<ArrayQueue<TaskId>>,
+    /// Whether the task is waiting to be polled; a task is queued only once
+    /// however many times it's woken
+    queued: AtomicBool,
+    ready_tasks: Arc<ReadyQueue>,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, ready_tasks: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    fn new(task_id: TaskId, ready_tasks: Arc<ReadyQueue>) -> Arc<Self> {
+        Arc::new(TaskWaker {
             task_id,
+            queued: AtomicBool::new(false),
             ready_tasks,
-        }))
+        })
     }
 
     fn wake_task(&self) {
-        self.ready_tasks
-            .push(self.task_id)
-            .expect("executor task_queue is full")
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.ready_tasks.push(self.task_id);
+        }
     }
 }
 
@@ -38,71 +81,201 @@ impl Wake for TaskWaker {
     }
 }
 
+/// Waker created when the task is spawned and reused for every poll
+struct CachedWaker {
+    state: Arc<TaskWaker>,
+    waker: Waker,
+}
+
+/// How long the executor slept waiting for interrupts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IdleStats {
+    /// Number of times the CPU was halted
+    pub sleeps: u64,
+    /// Total time spent halted
+    pub sleep_time: Duration,
+}
+
 pub struct EventLoopExecutor {
     tasks: BTreeMap<TaskId, Task>,
-    ready_tasks: Arc<ArrayQueue<TaskId>>,
-    // TODO cache wakers so we optmize memory usage
+    wakers: BTreeMap<TaskId, CachedWaker>,
+    ready_tasks: Arc<ReadyQueue>,
+    /// Futures submitted through a `Spawner`
+    injection: Arc<ArrayQueue<SendFuture>>,
+    /// Called every time no task is ready, before going to sleep
+    idle_hook: Option<fn()>,
+    idle_stats: IdleStats,
 }
 
 impl EventLoopExecutor {
     pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_READY_QUEUE_SIZE)
+    }
+
+    /// Creates an executor whose ready queue holds `capacity` tasks
+    pub fn with_capacity(capacity: usize) -> Self {
         Self {
             tasks: BTreeMap::new(),
-            // TODO make the queue size a parameter
-            ready_tasks: Arc::new(ArrayQueue::new(100)),
+            wakers: BTreeMap::new(),
+            ready_tasks: Arc::new(ReadyQueue::new(capacity)),
+            injection: Arc::new(ArrayQueue::new(INJECTION_QUEUE_SIZE)),
+            idle_hook: None,
+            idle_stats: IdleStats::default(),
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
-        let id = task.id;
-        self.tasks.insert(id, task);
-        self.ready_tasks
-            .push(id)
-            .expect("executor task_queue is full");
+    /// Sets a function called every time no task is ready, before the CPU
+    /// goes to sleep. Tasks it wakes or spawns run right away.
+    pub fn set_idle_hook(&mut self, hook: fn()) {
+        self.idle_hook = Some(hook);
     }
 
-    pub fn wrap_future(&mut self, future: impl Future<Output = ()> + 'static) {
-        self.spawn(Task::new(future))
+    pub fn idle_stats(&self) -> IdleStats {
+        self.idle_stats
     }
 
-    /// Run the event loop until no there's no more task left to be executed.
-    pub fn run<F>(&mut self, halt_func: F)
+    /// Handle to spawn tasks while the event loop is running
+    pub fn spawner(&self) -> Spawner {
+        Spawner::new(self.injection.clone())
+    }
+
+    /// Spawns a task, returning the handle to join or abort it
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
     where
-        F: Fn() -> (),
+        F: Future + 'static,
+        F::Output: 'static,
     {
-        while !self.tasks.is_empty() {
-            if let Some(selected_task_id) = self.ready_tasks.pop() {
-                let waker = TaskWaker::new(selected_task_id, self.ready_tasks.clone());
-                let ctx = &mut Context::from_waker(&waker);
-                let poll_result = self
-                    .tasks
-                    .get_mut(&selected_task_id)
-                    .map(|task| task.poll(ctx))
-                    .unwrap();
-                match poll_result {
-                    Poll::Ready(_) => {
-                        self.tasks.remove(&selected_task_id);
-                    }
-                    Poll::Pending => halt_func(),
+        let (task, handle) = joinable(future);
+        self.schedule(Task::new(task));
+        handle
+    }
+
+    /// Spawns a task, detached from the caller
+    pub fn wrap_future(&mut self, future: impl Future<Output = ()> + 'static) {
+        self.schedule(Task::new(future))
+    }
+
+    fn schedule(&mut self, task: Task) {
+        let id = task.id;
+        let state = TaskWaker::new(id, self.ready_tasks.clone());
+        let waker = Waker::from(state.clone());
+        self.tasks.insert(id, task);
+        self.wakers.insert(id, CachedWaker { state, waker });
+        self.wakers[&id].state.wake_task();
+    }
+
+    /// Next task to poll, going through the tasks woken while the ready
+    /// queue was full once it's empty
+    fn next_ready_task(&mut self) -> Option<TaskId> {
+        loop {
+            if let Some(id) = self.ready_tasks.queue.pop() {
+                return Some(id);
+            }
+            if !self.ready_tasks.overflowed.swap(false, Ordering::AcqRel) {
+                return None;
+            }
+            for (&id, cached) in self.wakers.iter() {
+                if cached.state.queued.load(Ordering::Acquire) {
+                    self.ready_tasks.push(id);
                 }
             }
         }
     }
+
+    /// Polls a task, unless it already completed or was already polled since
+    /// it was woken
+    fn poll_task(&mut self, id: TaskId) {
+        let (task, cached) = match (self.tasks.get_mut(&id), self.wakers.get(&id)) {
+            (Some(task), Some(cached)) => (task, cached),
+            _ => return,
+        };
+        if !cached.state.queued.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        let ctx = &mut Context::from_waker(&cached.waker);
+        if task.poll(ctx).is_ready() {
+            self.tasks.remove(&id);
+            self.wakers.remove(&id);
+        }
+    }
+
+    fn spawn_injected(&mut self) {
+        while let Some(future) = self.injection.pop() {
+            self.schedule(Task::from_pinned(future));
+        }
+    }
+
+    /// Whether a task is ready to run or waiting to be spawned
+    fn has_work(&self) -> bool {
+        !self.ready_tasks.is_empty() || !self.injection.is_empty()
+    }
+
+    /// Sleeps until an interrupt, unless some task got ready meanwhile.
+    /// The queues are checked with interrupts disabled, so a task woken by
+    /// an interrupt right after the check can't be missed: `CPU::idle`
+    /// enables them atomically with the halt.
+    fn idle<C: CPU>(&mut self, cpu: &C) {
+        if let Some(hook) = self.idle_hook {
+            hook();
+        }
+        // nothing can wake a task up without interrupts: keep polling
+        if !cpu.interrupts_enabled() {
+            return;
+        }
+        cpu.disable_interrupts();
+        if self.has_work() {
+            cpu.enable_interrupts();
+            return;
+        }
+        let start = Instant::now();
+        cpu.idle();
+        self.idle_stats.sleeps += 1;
+        self.idle_stats.sleep_time += start.elapsed();
+    }
+
+    /// Run the event loop until no there's no more task left to be executed.
+    /// When no task is ready the CPU sleeps until the next interrupt.
+    pub fn run<C: CPU>(&mut self, cpu: &C) {
+        loop {
+            self.spawn_injected();
+            if self.tasks.is_empty() {
+                break;
+            }
+            if let Some(selected_task_id) = self.next_ready_task() {
+                self.poll_task(selected_task_id);
+            } else {
+                self.idle(cpu);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use alloc::boxed::Box;
     use core::future::Future;
     use core::pin::Pin;
-    use core::sync::atomic::{AtomicBool, Ordering};
-    use core::task::{Context, Poll};
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use core::task::{Context, Poll, Waker};
+    use core::time::Duration;
 
-    use crate::runtime::executor::EventLoopExecutor;
+    use futures_util::future::pending;
+    use spin::Mutex;
+
+    use crate::hal::arch::x86_64::cpu::X86CPU;
+    use crate::hal::arch::x86_64::pit::set_timer_frequency;
+    use crate::kernel::cpu::CPU;
+    use crate::kernel::event_loop::executor::EventLoopExecutor;
+    use crate::kernel::event_loop::join::JoinError;
+    use crate::kernel::event_loop::spawner::{Spawner, INJECTION_QUEUE_SIZE};
+    use crate::kernel::irq::{is_registered, IrqReturn};
+    use crate::kernel::timer::sleep;
+    use crate::kernel::{time, TIMER_FREQUENCY};
 
     static IS_FUTURE_COMPLETE: AtomicBool = AtomicBool::new(false);
 
     struct TestableFuture {
-        event_loop: *mut EventLoopExecutor,
+        spawner: Spawner,
         first_call: bool,
     }
 
@@ -111,15 +284,14 @@ mod tests {
 
         fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
             match self.first_call {
-                true => unsafe {
+                true => {
                     let waker = cx.waker().clone();
                     self.first_call = false;
-                    self.event_loop
-                        .as_mut()
-                        .unwrap()
-                        .wrap_future(async { waker.wake() });
+                    self.spawner
+                        .spawn(async { waker.wake() })
+                        .expect("Unable to spawn task");
                     Poll::Pending
-                },
+                }
                 false => {
                     IS_FUTURE_COMPLETE.store(true, Ordering::Relaxed);
                     Poll::Ready(())
@@ -136,7 +308,7 @@ mod tests {
         let future = async { IS_FUTURE_COMPLETE.store(true, Ordering::Relaxed) };
 
         event_loop.wrap_future(future);
-        event_loop.run();
+        event_loop.run(&X86CPU::new());
 
         assert!(IS_FUTURE_COMPLETE.load(Ordering::Relaxed))
     }
@@ -146,12 +318,206 @@ mod tests {
         let mut event_loop = EventLoopExecutor::new();
         IS_FUTURE_COMPLETE.store(false, Ordering::Relaxed);
         let future = TestableFuture {
-            event_loop: &mut event_loop,
+            spawner: event_loop.spawner(),
             first_call: true,
         };
         event_loop.wrap_future(future);
-        event_loop.run();
+        event_loop.run(&X86CPU::new());
 
         assert!(IS_FUTURE_COMPLETE.load(Ordering::Relaxed))
     }
+
+    #[test_case]
+    fn test_spawn_before_run() {
+        let mut event_loop = EventLoopExecutor::new();
+        IS_FUTURE_COMPLETE.store(false, Ordering::Relaxed);
+        event_loop
+            .spawner()
+            .spawn(async { IS_FUTURE_COMPLETE.store(true, Ordering::Relaxed) })
+            .expect("Unable to spawn task");
+        event_loop.run(&X86CPU::new());
+
+        assert!(IS_FUTURE_COMPLETE.load(Ordering::Relaxed))
+    }
+
+    #[test_case]
+    fn test_spawn_boxed_gives_back_future_when_full() {
+        let mut event_loop = EventLoopExecutor::new();
+        let spawner = event_loop.spawner();
+        COMPLETED_TASKS.store(0, Ordering::Relaxed);
+        for _ in 0..INJECTION_QUEUE_SIZE {
+            let future = Box::pin(async {
+                COMPLETED_TASKS.fetch_add(1, Ordering::Relaxed);
+            });
+            assert!(spawner.spawn_boxed(future).is_ok());
+        }
+        let rejected = spawner
+            .spawn_boxed(Box::pin(async {}))
+            .expect_err("The injection queue should be full");
+        event_loop.run(&X86CPU::new());
+        drop(rejected);
+
+        assert_eq!(
+            COMPLETED_TASKS.load(Ordering::Relaxed),
+            INJECTION_QUEUE_SIZE
+        );
+    }
+
+    #[test_case]
+    fn test_join_task_output() {
+        let mut event_loop = EventLoopExecutor::new();
+        IS_FUTURE_COMPLETE.store(false, Ordering::Relaxed);
+        let handle = event_loop.spawn(async { 42 });
+        event_loop.wrap_future(async move {
+            assert_eq!(handle.await, Ok(42));
+            IS_FUTURE_COMPLETE.store(true, Ordering::Relaxed);
+        });
+        event_loop.run(&X86CPU::new());
+
+        assert!(IS_FUTURE_COMPLETE.load(Ordering::Relaxed))
+    }
+
+    static IS_FUTURE_DROPPED: AtomicBool = AtomicBool::new(false);
+
+    struct DropFlag;
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            IS_FUTURE_DROPPED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test_case]
+    fn test_abort_pending_task() {
+        let mut event_loop = EventLoopExecutor::new();
+        IS_FUTURE_COMPLETE.store(false, Ordering::Relaxed);
+        IS_FUTURE_DROPPED.store(false, Ordering::Relaxed);
+        let handle = event_loop.spawn(async {
+            let _flag = DropFlag;
+            pending::<()>().await;
+        });
+        event_loop.wrap_future(async move {
+            assert!(!handle.is_finished());
+            handle.abort();
+            assert_eq!(handle.await, Err(JoinError::Aborted));
+            assert!(IS_FUTURE_DROPPED.load(Ordering::Relaxed));
+            IS_FUTURE_COMPLETE.store(true, Ordering::Relaxed);
+        });
+        event_loop.run(&X86CPU::new());
+
+        assert!(IS_FUTURE_COMPLETE.load(Ordering::Relaxed))
+    }
+
+    static PARKED_WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+    fn wake_parked_task() {
+        if let Some(waker) = PARKED_WAKER.lock().take() {
+            waker.wake();
+        }
+    }
+
+    /// Pending until woken by the idle hook
+    struct ParkedFuture {
+        parked: bool,
+    }
+
+    impl Future for ParkedFuture {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            if self.parked {
+                return Poll::Ready(());
+            }
+            self.parked = true;
+            *PARKED_WAKER.lock() = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    #[test_case]
+    fn test_idle_hook_runs_without_ready_tasks() {
+        let mut event_loop = EventLoopExecutor::new();
+        IS_FUTURE_COMPLETE.store(false, Ordering::Relaxed);
+        event_loop.set_idle_hook(wake_parked_task);
+        event_loop.wrap_future(async {
+            ParkedFuture { parked: false }.await;
+            IS_FUTURE_COMPLETE.store(true, Ordering::Relaxed);
+        });
+        event_loop.run(&X86CPU::new());
+
+        assert!(IS_FUTURE_COMPLETE.load(Ordering::Relaxed));
+        // interrupts are disabled while testing, so the CPU is never halted
+        assert_eq!(event_loop.idle_stats().sleeps, 0);
+    }
+
+    /// IRQ line of the PIT channel 0
+    const TIMER_LINE: u8 = 0;
+
+    fn tick_handler(_line: u8) -> IrqReturn {
+        time::tick();
+        IrqReturn::Handled
+    }
+
+    #[test_case]
+    fn test_idle_sleeps_until_interrupt() {
+        time::tests::init_for_tests();
+        set_timer_frequency(TIMER_FREQUENCY);
+        let cpu = X86CPU::new();
+        let id = cpu
+            .register_interrupt_handler(TIMER_LINE, tick_handler)
+            .expect("Unable to register the timer handler");
+
+        let mut event_loop = EventLoopExecutor::new();
+        IS_FUTURE_COMPLETE.store(false, Ordering::Relaxed);
+        event_loop.wrap_future(async {
+            sleep(Duration::from_millis(3)).await;
+            IS_FUTURE_COMPLETE.store(true, Ordering::Relaxed);
+        });
+        cpu.enable_interrupts();
+        event_loop.run(&cpu);
+        cpu.disable_interrupts();
+        cpu.unregister_interrupt_handler(id)
+            .expect("Unable to unregister the timer handler");
+        // the line is masked again, so later tests don't get timer interrupts
+        assert!(!is_registered(TIMER_LINE));
+
+        assert!(IS_FUTURE_COMPLETE.load(Ordering::Relaxed));
+        let stats = event_loop.idle_stats();
+        assert!(stats.sleeps > 0);
+        assert!(stats.sleep_time > Duration::from_secs(0));
+    }
+
+    static COMPLETED_TASKS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Wakes itself several times before completing
+    struct RestlessFuture {
+        polls: usize,
+    }
+
+    impl Future for RestlessFuture {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            if self.polls == 2 {
+                COMPLETED_TASKS.fetch_add(1, Ordering::Relaxed);
+                return Poll::Ready(());
+            }
+            self.polls += 1;
+            cx.waker().wake_by_ref();
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test_case]
+    fn test_duplicate_wakeups_and_full_queue() {
+        let mut event_loop = EventLoopExecutor::with_capacity(1);
+        COMPLETED_TASKS.store(0, Ordering::Relaxed);
+        for _ in 0..3 {
+            event_loop.wrap_future(RestlessFuture { polls: 0 });
+        }
+        event_loop.run(&X86CPU::new());
+
+        assert_eq!(COMPLETED_TASKS.load(Ordering::Relaxed), 3);
+    }
 }
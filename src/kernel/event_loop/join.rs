@@ -0,0 +1,138 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted before completing, or its output was already
+    /// taken by a previous join
+    Aborted,
+}
+
+/// State shared by a task and its `JoinHandle`
+struct JoinState<T> {
+    output: Option<T>,
+    /// Whether the task completed or was aborted: its future is dropped
+    finished: bool,
+    abort_requested: bool,
+    /// Wakes the task to process an abort request
+    task: Option<Waker>,
+    /// Wakes the task awaiting the handle
+    joiner: Option<Waker>,
+}
+
+/// Creates the future run by the executor for the given future, and the
+/// handle to join it.
+pub(super) fn joinable<F>(future: F) -> (Joinable<F>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        finished: false,
+        abort_requested: false,
+        task: None,
+        joiner: None,
+    }));
+    let task = Joinable {
+        future: Some(Box::pin(future)),
+        state: state.clone(),
+    };
+    (task, JoinHandle { state })
+}
+
+/// Wraps a task future, storing its output for the `JoinHandle`
+pub(super) struct Joinable<F: Future> {
+    future: Option<Pin<Box<F>>>,
+    state: Arc<Mutex<JoinState<F::Output>>>,
+}
+
+impl<F: Future> Joinable<F> {
+    fn finish(&mut self, output: Option<F::Output>) -> Poll<()> {
+        // the future is dropped before the joiner is woken
+        self.future = None;
+        let joiner = {
+            let mut state = self.state.lock();
+            state.output = output;
+            state.finished = true;
+            state.task = None;
+            state.joiner.take()
+        };
+        if let Some(joiner) = joiner {
+            joiner.wake();
+        }
+        Poll::Ready(())
+    }
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        // the lock isn't held while polling, so the task can abort itself
+        let abort_requested = {
+            let mut state = self.state.lock();
+            state.task = Some(ctx.waker().clone());
+            state.abort_requested
+        };
+        if abort_requested {
+            return self.finish(None);
+        }
+        let output = match self.future.as_mut() {
+            Some(future) => future.as_mut().poll(ctx),
+            None => return Poll::Ready(()),
+        };
+        match output {
+            Poll::Ready(output) => self.finish(Some(output)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Handle to a spawned task, which can be awaited for the task's output.
+/// Dropping the handle detaches the task, which keeps running.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Whether the task completed or was aborted
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+
+    /// Aborts the task: its future is dropped the next time the executor
+    /// picks it, and then the handle resolves to `JoinError::Aborted`.
+    /// Does nothing if the task already completed.
+    pub fn abort(&self) {
+        let task = {
+            let mut state = self.state.lock();
+            if state.finished {
+                return;
+            }
+            state.abort_requested = true;
+            state.task.take()
+        };
+        if let Some(task) = task {
+            task.wake();
+        }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if state.finished {
+            Poll::Ready(state.output.take().ok_or(JoinError::Aborted))
+        } else {
+            state.joiner = Some(ctx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
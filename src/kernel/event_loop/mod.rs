@@ -1,2 +1,4 @@
 pub mod executor;
+pub mod join;
+pub mod spawner;
 mod task;
@@ -0,0 +1,58 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+
+use crossbeam_queue::ArrayQueue;
+
+use super::join::{joinable, JoinHandle};
+
+/// Number of tasks that can wait in the injection queue until the executor
+/// picks them
+pub const INJECTION_QUEUE_SIZE: usize = 100;
+
+/// Task future boxed beforehand, see `Spawner::spawn_boxed`
+pub type SendFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The injection queue is full
+    QueueFull,
+}
+
+/// Handle to spawn tasks on an `EventLoopExecutor` from anywhere, including
+/// the tasks it's running. Spawned futures are pushed to an injection queue
+/// and the executor picks them before polling its ready tasks.
+///
+/// `spawn` boxes the future, so it can't be called in interrupt context:
+/// interrupt handlers use `spawn_boxed` with a future boxed beforehand.
+#[derive(Clone)]
+pub struct Spawner {
+    injection: Arc<ArrayQueue<SendFuture>>,
+}
+
+impl Spawner {
+    pub(super) fn new(injection: Arc<ArrayQueue<SendFuture>>) -> Self {
+        Self { injection }
+    }
+
+    /// Spawns a task, returning the handle to join or abort it
+    pub fn spawn<F>(&self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = joinable(future);
+        self.injection
+            .push(Box::pin(task))
+            .map_err(|_| SpawnError::QueueFull)?;
+        Ok(handle)
+    }
+
+    /// Spawns a task boxed beforehand, without allocating, so it can be
+    /// called in interrupt context. The task is detached. If the injection
+    /// queue is full the future is given back, so it isn't freed here either.
+    pub fn spawn_boxed(&self, future: SendFuture) -> Result<(), SendFuture> {
+        self.injection.push(future)
+    }
+}
@@ -22,9 +22,13 @@ pub struct Task {
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+        Self::from_pinned(Box::pin(future))
+    }
+
+    pub(crate) fn from_pinned(future: Pin<Box<dyn Future<Output = ()>>>) -> Self {
         Self {
             id: TaskId::new(),
-            future: Box::pin(future),
+            future,
         }
     }
 
@@ -0,0 +1,122 @@
+//! CPU exceptions handling policy.
+//! The HAL resolves what it can by itself (demand paging, copy on write) and
+//! delivers any other exception to the handler registered through the `CPU`
+//! trait, which decides whether the execution resumes or the kernel panics.
+//! The handler can also redirect the execution, by changing the instruction
+//! pointer, the stack pointer or the registers it resumes with: e.g. to skip
+//! the faulting instruction or to jump to a recovery routine.
+//! Without a handler, traps (debug and breakpoint) resume and everything
+//! else panics.
+//!
+//! Double faults and machine checks can't be recovered from: the handler
+//! isn't called for them.
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::hal::arch::x86_64::cpu::{InterruptionDetails, InterruptionType};
+
+/// What to do once an exception has been handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionAction {
+    /// The cause was dealt with: resume the execution at the handler's
+    /// instruction pointer. Unless the handler changed it, the faulting
+    /// instruction is retried (or the execution continues after it, for traps)
+    Resume,
+    /// Print the crash report and panic
+    Panic,
+}
+
+/// Exception handler, called in interrupt context (see `kernel::heap`) with
+/// the CPU state, which it can change before resuming.
+pub type ExceptionHandler = fn(&mut InterruptionDetails) -> ExceptionAction;
+
+static HANDLER: Mutex<Option<ExceptionHandler>> = Mutex::new(None);
+
+/// Sets the exception handler, replacing the previous one
+pub fn set_handler(handler: ExceptionHandler) {
+    without_interrupts(|| *HANDLER.lock() = Some(handler));
+}
+
+/// Removes the exception handler, going back to the default policy
+pub fn clear_handler() {
+    without_interrupts(|| *HANDLER.lock() = None);
+}
+
+/// Decides what to do with an exception.
+/// Must be called in interrupt context.
+pub fn dispatch(details: &mut InterruptionDetails) -> ExceptionAction {
+    // exceptions can't be masked, so one may happen while the handler
+    // is being replaced
+    let handler = HANDLER.try_lock().and_then(|handler| *handler);
+    match handler {
+        Some(handler) => handler(details),
+        None => default_action(details.kind),
+    }
+}
+
+/// Policy used when no handler is registered
+pub fn default_action(kind: InterruptionType) -> ExceptionAction {
+    match kind {
+        InterruptionType::Debug | InterruptionType::Breakpoint => ExceptionAction::Resume,
+        _ => ExceptionAction::Panic,
+    }
+}
+
+/// Kernel exception policy: traps are reported and resumed, faults are fatal
+/// as long as there are no user tasks to kill instead.
+pub fn kernel_exception_handler(details: &mut InterruptionDetails) -> ExceptionAction {
+    match default_action(details.kind) {
+        ExceptionAction::Resume => {
+            crate::kprintln!("{}", details);
+            ExceptionAction::Resume
+        }
+        ExceptionAction::Panic => ExceptionAction::Panic,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::{clear_handler, default_action, set_handler, ExceptionAction};
+    use crate::hal::arch::x86_64::cpu::{InterruptionDetails, InterruptionType};
+
+    /// Length of the `ud2` instruction
+    const UD2_LENGTH: u64 = 2;
+
+    static FAULT_RAX: AtomicU64 = AtomicU64::new(0);
+
+    /// Skips the invalid instruction, incrementing `rax`
+    fn skip_invalid_opcode(details: &mut InterruptionDetails) -> ExceptionAction {
+        if details.kind != InterruptionType::InvalidOpcode {
+            return default_action(details.kind);
+        }
+        FAULT_RAX.store(details.registers.rax, Ordering::SeqCst);
+        details.registers.rax += 1;
+        details.instruction_pointer += UD2_LENGTH;
+        ExceptionAction::Resume
+    }
+
+    #[test_case]
+    fn test_handler_redirects_execution() {
+        set_handler(skip_invalid_opcode);
+        let mut rax: u64 = 41;
+        unsafe { asm!("ud2", inout("rax") rax) };
+        clear_handler();
+
+        assert_eq!(FAULT_RAX.load(Ordering::SeqCst), 41);
+        assert_eq!(rax, 42);
+    }
+
+    #[test_case]
+    fn test_default_action() {
+        assert_eq!(
+            default_action(InterruptionType::Breakpoint),
+            ExceptionAction::Resume
+        );
+        assert_eq!(
+            default_action(InterruptionType::GeneralProtectionFault),
+            ExceptionAction::Panic
+        );
+    }
+}
@@ -5,53 +5,373 @@
 //! [BuddyAllocator](https://github.com/jjyr/buddy-alloc) library.
 //! The allocator takes care only of allocating the virtual memory,
 //! and therefore requires that physical memory is already allocated.
+//!
+//! The heap reserves a virtual window starting at `HEAP_START_ADDRESS`.
+//! Only the first `HEAP_SIZE` bytes are mapped on boot; once growth is
+//! enabled, every time the existing regions can't satisfy an allocation
+//! a new region is mapped right after the previous ones and a new
+//! `BuddyAlloc` is created to manage it.
+//!
+//! Growing the heap maps memory while the heap lock is held, so the lock
+//! order is: heap, then `Memory`. Code holding the `Memory` lock must never
+//! allocate from the heap; doing so is detected when the heap needs to grow
+//! and reported with a panic instead of deadlocking.
+//!
+//! Interrupt and exception handlers may run while the interrupted code holds
+//! the heap lock, so code running in interrupt context must neither allocate
+//! from the heap nor wait for a lock the interrupted code could hold.
 use alloc::alloc::{GlobalAlloc, Layout};
+use core::mem;
 
 use buddy_alloc::buddy_alloc::BuddyAlloc;
 use buddy_alloc::BuddyAllocParam;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
 
 use crate::commons::Locked;
+use crate::hal::arch::x86_64::memory::memory;
+use crate::hal::arch::x86_64::protection::kernel_data_flags;
+use crate::kernel::heap_tracker::AllocationTracker;
+use crate::kernel::slab::{SlabAllocator, SlabStats, SLAB_CACHES, SLAB_SIZE};
+use crate::kernel::{HEAP_GROWTH_SIZE, HEAP_MAX_SIZE, HEAP_SIZE, HEAP_START_ADDRESS};
+use crate::runtime::allocator::kernel_heap;
+
+/// Maximum number of regions the heap can be made of
+const MAX_HEAP_REGIONS: usize = 64;
+
+/// Function used by the heap to back a virtual memory range with physical
+/// memory. Receives the range start address and size, and returns whether
+/// the memory was successfully mapped.
+pub type HeapGrowFn = fn(usize, usize) -> bool;
+
+/// A contiguous piece of the heap managed by its own `BuddyAlloc`.
+struct HeapRegion {
+    start: usize,
+    size: usize,
+    buddy_alloc: BuddyAlloc,
+}
+
+impl HeapRegion {
+    /// Unsafe because the memory range must be already mapped.
+    unsafe fn new(start: usize, size: usize, block_size: usize) -> Self {
+        let params = BuddyAllocParam::new(start as *const u8, size, block_size);
+        Self {
+            start,
+            size,
+            buddy_alloc: BuddyAlloc::new(params),
+        }
+    }
+
+    fn contains(&self, ptr: *mut u8) -> bool {
+        let addr = ptr as usize;
+        addr >= self.start && addr < self.start + self.size
+    }
+
+    /// Returns the size of the largest free block, read from the free lists
+    /// of the buddy allocator.
+    ///
+    /// `BuddyAlloc` doesn't expose its free lists, so they're found where
+    /// `BuddyAlloc::new` (buddy-alloc 0.4.1, pinned in Cargo.toml) lays them
+    /// out: the region starts with one entry of 3 pointers per block order,
+    /// followed by the head node (2 pointers) of the free list of each order.
+    /// A list is empty when its head points to itself.
+    fn largest_free_block(&self, block_size: usize) -> usize {
+        let word = mem::size_of::<usize>();
+        let base = align_up(self.start, block_size);
+        let blocks = (self.start + self.size - base) / block_size;
+        // block orders, plus an unused one on top
+        let orders = (word * 8 - 1 - blocks.leading_zeros() as usize) + 2;
+        let heads = base + orders * 3 * word;
+        (0..orders)
+            .rev()
+            .find(|order| {
+                let head = heads + order * 2 * word;
+                unsafe { *(head as *const usize) != head }
+            })
+            .map_or(0, |order| block_size << order)
+    }
+}
+
+const NO_REGION: Option<HeapRegion> = None;
+
+/// Kernel heap usage statistics
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes currently allocated
+    pub bytes_in_use: usize,
+    /// Highest value `bytes_in_use` ever reached
+    pub peak_bytes_in_use: usize,
+    /// Total number of allocations
+    pub allocations: usize,
+    /// Total number of frees
+    pub frees: usize,
+    /// Bytes free in the buddy allocators (slab free objects are
+    /// accounted as used, as they are only available for their caches)
+    pub available_bytes: usize,
+    /// Size of the largest block that can be allocated without growing the heap
+    pub largest_free_block: usize,
+    /// Percentage of the available bytes that can't be used for a single
+    /// allocation of `available_bytes`
+    pub fragmentation: usize,
+}
 
 /// Wraps the 3rd party Allocator implementation.
-/// The actual allocator is created in a lazy fashion,
-/// So initially it's a `None` Option and only the heap
+/// The actual allocators are created in a lazy fashion,
+/// So initially there are no regions and only the heap
 /// params are defined.
+/// Small objects are served by a `SlabAllocator`, which takes its slabs
+/// from the buddy allocators.
 pub struct HeapAllocator {
-    buddy_alloc: Option<BuddyAlloc>,
-    heap_params: BuddyAllocParam,
+    regions: [Option<HeapRegion>; MAX_HEAP_REGIONS],
+    slabs: SlabAllocator,
+    start: usize,
+    /// Bytes of the heap virtual window already backed by physical memory
+    committed: usize,
+    /// Maximum number of bytes the heap can grow to
+    max_size: usize,
+    block_size: usize,
+    grow_fn: Option<HeapGrowFn>,
+    bytes_in_use: usize,
+    peak_bytes_in_use: usize,
+    allocations: usize,
+    frees: usize,
+    tracker: AllocationTracker,
 }
 
 impl HeapAllocator {
     pub(crate) const fn new(start: usize, size: usize, block_size: usize) -> Self {
         HeapAllocator {
-            buddy_alloc: None,
-            heap_params: BuddyAllocParam::new(start as *const u8, size, block_size),
+            regions: [NO_REGION; MAX_HEAP_REGIONS],
+            // buddy blocks are aligned to the block size, and so are the slabs
+            slabs: SlabAllocator::new(block_size),
+            start,
+            committed: size,
+            max_size: size,
+            block_size,
+            grow_fn: None,
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            allocations: 0,
+            frees: 0,
+            tracker: AllocationTracker::new(),
         }
     }
 
-    /// This function executes the given closure, which takes the actual
-    /// `BuddyAlloc` as parameter.
+    /// Allows the heap to grow up to `max_size` bytes, using `grow_fn`
+    /// to map the memory for each new region.
+    pub fn enable_growth(&mut self, max_size: usize, grow_fn: HeapGrowFn) {
+        assert!(
+            max_size >= self.committed,
+            "Heap max size smaller than its current size"
+        );
+        self.max_size = max_size;
+        self.grow_fn = Some(grow_fn);
+    }
+
+    /// This function executes the given closure, which takes the
+    /// heap regions as parameter.
     /// This mechanism is necessary to make the lazy initialization of the
     /// Allocator.
-    fn exec<R, F: FnOnce(&mut BuddyAlloc) -> R>(&mut self, func: F) -> R {
-        if self.buddy_alloc.is_none() {
+    fn exec<R, F: FnOnce(&mut [Option<HeapRegion>]) -> R>(&mut self, func: F) -> R {
+        if self.regions[0].is_none() {
             unsafe {
                 // The BuddyAlloc::new is unsafe, cause there's no guarantee
                 // that the page frames are actually initialized.
-                let alloc = BuddyAlloc::new(self.heap_params);
-                self.buddy_alloc = Some(alloc);
+                self.regions[0] =
+                    Some(HeapRegion::new(self.start, self.committed, self.block_size));
             }
         }
-        // gets a mutable reference to the allocator
-        let mut_alloc = self.buddy_alloc.as_mut().expect("Allocator not present");
-        func(mut_alloc)
+        func(&mut self.regions)
+    }
+
+    /// Allocates memory for the given layout, updating the heap statistics.
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc_block(layout);
+        if !ptr.is_null() {
+            self.allocations += 1;
+            self.bytes_in_use += layout.size();
+            self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+            self.tracker.on_alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    /// Frees memory allocated with `alloc` for the same layout, updating
+    /// the heap statistics.
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.frees += 1;
+        self.bytes_in_use -= layout.size();
+        self.tracker.on_dealloc(ptr);
+        self.dealloc_block(ptr, layout)
+    }
+
+    /// Allocates the memory block for the given layout.
+    /// Small layouts are served by the slab caches.
+    /// The buddy blocks are always aligned to the block size, so layouts
+    /// requiring a larger alignment are over-allocated: the returned pointer
+    /// is aligned inside the block and the block address is stored right
+    /// before it, to be used when freeing the memory.
+    fn alloc_block(&mut self, layout: Layout) -> *mut u8 {
+        if let Some(index) = self.slabs.cache_index(layout.size(), layout.align()) {
+            if self.slabs.needs_slab(index) {
+                let slab = self.malloc(SLAB_SIZE);
+                if slab.is_null() {
+                    return slab;
+                }
+                unsafe { self.slabs.add_slab(index, slab) }
+            }
+            return self.slabs.alloc(index);
+        }
+
+        if layout.align() <= self.block_size {
+            return self.malloc(layout.size());
+        }
+
+        let size = match layout.size().checked_add(layout.align()) {
+            Some(size) => size,
+            None => return core::ptr::null_mut(),
+        };
+        let block = self.malloc(size);
+        if block.is_null() {
+            return block;
+        }
+        let header_size = core::mem::size_of::<usize>();
+        let aligned = align_up(block as usize + header_size, layout.align());
+        unsafe {
+            *((aligned - header_size) as *mut usize) = block as usize;
+        }
+        aligned as *mut u8
+    }
+
+    /// Frees a block allocated with `alloc_block` for the same layout.
+    fn dealloc_block(&mut self, ptr: *mut u8, layout: Layout) {
+        if let Some(index) = self.slabs.cache_index(layout.size(), layout.align()) {
+            return unsafe { self.slabs.free(index, ptr) };
+        }
+
+        if layout.align() <= self.block_size {
+            return self.free(ptr);
+        }
+
+        let header_size = core::mem::size_of::<usize>();
+        let block = unsafe { *((ptr as usize - header_size) as *const usize) };
+        self.free(block as *mut u8)
+    }
+
+    /// Allocates `size` bytes, growing the heap if none of the
+    /// existing regions has room for it.
+    /// Returns a null pointer if the memory can't be allocated.
+    fn malloc(&mut self, size: usize) -> *mut u8 {
+        let ptr = self.exec(|regions| {
+            for region in regions.iter_mut().flatten() {
+                let ptr = region.buddy_alloc.malloc(size);
+                if !ptr.is_null() {
+                    return ptr;
+                }
+            }
+            core::ptr::null_mut()
+        });
+        if !ptr.is_null() {
+            return ptr;
+        }
+
+        match self.grow(size) {
+            Some(region) => region.buddy_alloc.malloc(size),
+            None => core::ptr::null_mut(),
+        }
+    }
+
+    fn free(&mut self, ptr: *mut u8) {
+        self.exec(|regions| {
+            let region = regions
+                .iter_mut()
+                .flatten()
+                .find(|region| region.contains(ptr))
+                .expect("Freeing memory outside the heap");
+            region.buddy_alloc.free(ptr)
+        })
+    }
+
+    /// Maps a new region able to hold an allocation of `size` bytes.
+    fn grow(&mut self, size: usize) -> Option<&mut HeapRegion> {
+        let grow_fn = self.grow_fn?;
+        let slot = self.regions.iter().position(|region| region.is_none())?;
+
+        // the buddy allocator keeps its metadata inside the region,
+        // so the region needs to be larger than the requested block.
+        let required = HEAP_GROWTH_SIZE.max(size.checked_mul(2)?.next_power_of_two());
+        let remaining = self.max_size - self.committed;
+        if required > remaining {
+            return None;
+        }
+        // the heap doubles its size each time it grows, so the number of
+        // regions doesn't limit how large it can get
+        let region_size = required.max(self.committed).min(remaining);
+
+        let region_start = self.start + self.committed;
+        if !grow_fn(region_start, region_size) {
+            return None;
+        }
+        self.committed += region_size;
+        unsafe {
+            self.regions[slot] = Some(HeapRegion::new(region_start, region_size, self.block_size));
+        }
+        self.regions[slot].as_mut()
     }
 
     /// Returns the number of bytes still available in the heap
     pub fn available_bytes(&mut self) -> usize {
-        return self.exec(|alloc| alloc.available_bytes());
+        return self.exec(|regions| {
+            regions
+                .iter_mut()
+                .flatten()
+                .map(|region| region.buddy_alloc.available_bytes())
+                .sum()
+        });
+    }
+
+    /// Returns the size of the largest block that can be allocated without
+    /// growing the heap.
+    pub fn largest_free_block(&mut self) -> usize {
+        let block_size = self.block_size;
+        self.exec(|regions| {
+            regions
+                .iter()
+                .flatten()
+                .map(|region| region.largest_free_block(block_size))
+                .max()
+                .unwrap_or(0)
+        })
+    }
+
+    /// Returns the heap usage statistics
+    pub fn stats(&mut self) -> HeapStats {
+        let available_bytes = self.available_bytes();
+        let largest_free_block = self.largest_free_block();
+        let fragmentation = match available_bytes {
+            0 => 0,
+            available => 100 - largest_free_block.min(available) * 100 / available,
+        };
+        HeapStats {
+            bytes_in_use: self.bytes_in_use,
+            peak_bytes_in_use: self.peak_bytes_in_use,
+            allocations: self.allocations,
+            frees: self.frees,
+            available_bytes,
+            largest_free_block,
+            fragmentation,
+        }
+    }
+
+    /// Returns the tracker recording the tagged allocations
+    pub fn tracker(&mut self) -> &mut AllocationTracker {
+        &mut self.tracker
+    }
+
+    /// Returns the usage statistics of the slab caches
+    pub fn slab_stats(&self) -> [SlabStats; SLAB_CACHES] {
+        self.slabs.stats()
     }
-    // TODO add a function to retrieve the number of bytes in use
 }
 
 /// Implement the `GlobalAlloc` trait for the `Locked<HeapAllocator>`.
@@ -59,15 +379,166 @@ impl HeapAllocator {
 /// the `HeapAllocator` thread-safe. This trick is necessary to, once again,
 /// be able to initialize the static global allocator.
 /// The implementation of both `alloc` and `dealloc` only locks the `HeapAllocator`,
-/// and then calls the `exec` method passing a closure that will call the underlying
-/// `BuddyAlloc`.
+/// and then calls its `alloc`/`dealloc` methods, which take care of the
+/// layout alignment.
 unsafe impl GlobalAlloc for Locked<HeapAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.lock().exec(|alloc| alloc.malloc(layout.size()))
+        self.lock().alloc(layout)
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        self.lock().exec(|alloc| alloc.free(ptr))
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().dealloc(ptr, layout)
     }
 }
 unsafe impl Sync for Locked<HeapAllocator> {}
+
+/// Maps the initial `HEAP_SIZE` bytes of the kernel heap and enables it to
+/// grow on demand, up to `HEAP_MAX_SIZE`.
+/// Requires the global `Memory` to be initialized.
+pub fn init_heap() {
+    memory()
+        .lock()
+        .alloc_frames(
+            VirtAddr::new(HEAP_START_ADDRESS as u64),
+            HEAP_SIZE,
+            heap_flags(),
+        )
+        .expect("Unable to allocate frames for Kernel heap");
+    kernel_heap().lock().enable_growth(HEAP_MAX_SIZE, grow_heap);
+}
+
+/// `HeapGrowFn` backing new heap regions with frames from the global `Memory`.
+/// Called with the heap lock held, so the `Memory` lock must be free: if it's
+/// taken, the allocation comes from code holding it (there's a single CPU),
+/// which would deadlock.
+fn grow_heap(start: usize, size: usize) -> bool {
+    let mut mem = memory()
+        .try_lock()
+        .expect("Heap allocation while holding the Memory lock");
+    let start = VirtAddr::new(start as u64);
+    if mem.alloc_frames(start, size, heap_flags()).is_err() {
+        // release whatever was mapped before the failure
+        unsafe {
+            let _ = mem.free_frames(start, size);
+        }
+        return false;
+    }
+    true
+}
+
+fn heap_flags() -> PageTableFlags {
+    kernel_data_flags()
+}
+
+/// Rounds `addr` up to the next multiple of `align`, which must be a power of two
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    const PAGE_SIZE: usize = 4096;
+
+    /// Allocates every combination of the given sizes and power of two
+    /// alignments (1 byte up to 4 pages), checking the returned pointers.
+    fn check_alignment(sizes: &[usize]) {
+        for &size in sizes {
+            let mut align = 1;
+            while align <= 4 * PAGE_SIZE {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                unsafe {
+                    let ptr = alloc(layout);
+                    assert!(!ptr.is_null(), "allocation failed for {:?}", layout);
+                    assert_eq!(ptr as usize % align, 0, "misaligned {:?}", layout);
+                    // the whole block must be usable
+                    core::ptr::write_bytes(ptr, 0xAB, size);
+                    dealloc(ptr, layout);
+                }
+                align *= 2;
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_alignment_small_allocations() {
+        check_alignment(&[1, 3, 8, 24, 64, 100, 255]);
+    }
+
+    #[test_case]
+    fn test_alignment_page_allocations() {
+        check_alignment(&[PAGE_SIZE - 1, PAGE_SIZE, PAGE_SIZE + 1, 3 * PAGE_SIZE + 17]);
+    }
+
+    #[test_case]
+    fn test_small_objects_are_served_by_slabs() {
+        use crate::runtime::allocator::kernel_heap;
+        use alloc::boxed::Box;
+
+        // 24 bytes objects belong to the 32 bytes cache
+        let allocations = || kernel_heap().lock().slab_stats()[1].allocations;
+        let before = allocations();
+        let boxed = Box::new([0u8; 24]);
+        assert_eq!(allocations(), before + 1);
+        drop(boxed);
+    }
+
+    #[test_case]
+    fn test_stats_track_bytes_in_use() {
+        use crate::runtime::allocator::kernel_heap;
+        use alloc::vec::Vec;
+
+        let before = kernel_heap().lock().stats();
+        let buffer: Vec<u8> = Vec::with_capacity(1000);
+        let during = kernel_heap().lock().stats();
+        assert_eq!(during.bytes_in_use, before.bytes_in_use + 1000);
+        assert_eq!(during.allocations, before.allocations + 1);
+        assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+        assert!(during.largest_free_block <= during.available_bytes);
+
+        drop(buffer);
+        let after = kernel_heap().lock().stats();
+        assert_eq!(after.bytes_in_use, before.bytes_in_use);
+        assert_eq!(after.frees, before.frees + 1);
+    }
+
+    #[repr(align(4096))]
+    struct RegionMemory([u8; REGION_SIZE]);
+
+    const REGION_SIZE: usize = 64 * 1024;
+    static mut REGION_MEMORY: RegionMemory = RegionMemory([0; REGION_SIZE]);
+
+    #[test_case]
+    fn test_largest_free_block_matches_the_buddy_allocator() {
+        use super::HeapRegion;
+
+        let start = unsafe { REGION_MEMORY.0.as_mut_ptr() } as usize;
+        let mut region = unsafe { HeapRegion::new(start, REGION_SIZE, 16) };
+        let largest = region.largest_free_block(16);
+        // the allocator metadata takes the beginning of the region
+        assert_eq!(largest, REGION_SIZE / 2);
+        assert!(region.buddy_alloc.malloc(largest * 2).is_null());
+
+        let ptr = region.buddy_alloc.malloc(largest);
+        assert!(!ptr.is_null());
+        assert!(region.largest_free_block(16) < largest);
+        region.buddy_alloc.free(ptr);
+        assert_eq!(region.largest_free_block(16), largest);
+    }
+
+    #[test_case]
+    fn test_aligned_allocations_do_not_overlap() {
+        let layout = Layout::from_size_align(100, 64).unwrap();
+        unsafe {
+            let first = alloc(layout);
+            let second = alloc(layout);
+            core::ptr::write_bytes(first, 1, 100);
+            core::ptr::write_bytes(second, 2, 100);
+            assert_eq!(*first.add(99), 1);
+            assert_eq!(*second, 2);
+            dealloc(first, layout);
+            dealloc(second, layout);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Debugging aid to find heap leaks.
+//! While a tag is active, every allocation made by the kernel heap is
+//! recorded with that tag until it's freed, so it's possible to check
+//! whether a subsystem released everything it allocated.
+//! Records are kept in a fixed size table, as the tracker can't use the heap itself.
+//! While no allocation is recorded, the tracker costs a counter check on
+//! every free.
+use crate::runtime::allocator::kernel_heap;
+
+/// Maximum number of allocations tracked at the same time
+const MAX_TRACKED_ALLOCATIONS: usize = 512;
+
+#[derive(Clone, Copy)]
+struct TrackedAllocation {
+    ptr: usize,
+    size: usize,
+    tag: &'static str,
+}
+
+/// Allocations still not freed for a given tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutstandingAllocations {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+pub struct AllocationTracker {
+    tag: Option<&'static str>,
+    allocations: [Option<TrackedAllocation>; MAX_TRACKED_ALLOCATIONS],
+    /// Number of records in `allocations`
+    recorded: usize,
+    /// Allocations that couldn't be recorded because the table was full
+    untracked: usize,
+}
+
+impl AllocationTracker {
+    pub const fn new() -> Self {
+        Self {
+            tag: None,
+            allocations: [None; MAX_TRACKED_ALLOCATIONS],
+            recorded: 0,
+            untracked: 0,
+        }
+    }
+
+    /// Sets the tag used for the next allocations, returning the previous one
+    pub fn set_tag(&mut self, tag: Option<&'static str>) -> Option<&'static str> {
+        core::mem::replace(&mut self.tag, tag)
+    }
+
+    pub(crate) fn on_alloc(&mut self, ptr: *mut u8, size: usize) {
+        if let Some(tag) = self.tag {
+            let record = TrackedAllocation {
+                ptr: ptr as usize,
+                size,
+                tag,
+            };
+            match self.allocations.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => {
+                    *slot = Some(record);
+                    self.recorded += 1;
+                }
+                None => self.untracked += 1,
+            }
+        }
+    }
+
+    pub(crate) fn on_dealloc(&mut self, ptr: *mut u8) {
+        if self.recorded == 0 {
+            return;
+        }
+        let ptr = ptr as usize;
+        if let Some(slot) = self
+            .allocations
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |record| record.ptr == ptr))
+        {
+            *slot = None;
+            self.recorded -= 1;
+        }
+    }
+
+    /// Returns the allocations made with the given tag that weren't freed yet
+    pub fn outstanding(&self, tag: &str) -> OutstandingAllocations {
+        self.allocations
+            .iter()
+            .flatten()
+            .filter(|record| record.tag == tag)
+            .fold(
+                OutstandingAllocations { count: 0, bytes: 0 },
+                |acc, record| OutstandingAllocations {
+                    count: acc.count + 1,
+                    bytes: acc.bytes + record.size,
+                },
+            )
+    }
+
+    /// Number of allocations that couldn't be tracked since the table was full
+    pub fn untracked(&self) -> usize {
+        self.untracked
+    }
+}
+
+/// Guard returned by `track_allocations`; restores the previous tag when dropped.
+pub struct TrackingGuard {
+    previous_tag: Option<&'static str>,
+}
+
+impl Drop for TrackingGuard {
+    fn drop(&mut self) {
+        kernel_heap().lock().tracker().set_tag(self.previous_tag);
+    }
+}
+
+/// Tags every kernel heap allocation made while the returned guard is alive.
+///
+/// ```ignore
+/// {
+///     let _guard = track_allocations("executor");
+///     // ...
+/// }
+/// assert_eq!(outstanding_allocations("executor").count, 0);
+/// ```
+pub fn track_allocations(tag: &'static str) -> TrackingGuard {
+    let previous_tag = kernel_heap().lock().tracker().set_tag(Some(tag));
+    TrackingGuard { previous_tag }
+}
+
+/// Returns the allocations made with the given tag that weren't freed yet
+pub fn outstanding_allocations(tag: &str) -> OutstandingAllocations {
+    kernel_heap().lock().tracker().outstanding(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    use super::{outstanding_allocations, track_allocations};
+
+    #[test_case]
+    fn test_freed_allocations_are_not_outstanding() {
+        {
+            let _guard = track_allocations("test-freed");
+            let mut values = Vec::new();
+            for value in 0..100u64 {
+                values.push(value);
+            }
+        }
+        assert_eq!(outstanding_allocations("test-freed").count, 0);
+    }
+
+    #[test_case]
+    fn test_outstanding_allocations_are_reported() {
+        let leaked = {
+            let _guard = track_allocations("test-leak");
+            Box::new([0u64; 8])
+        };
+        let outstanding = outstanding_allocations("test-leak");
+        assert_eq!(outstanding.count, 1);
+        assert_eq!(outstanding.bytes, 64);
+
+        drop(leaked);
+        assert_eq!(outstanding_allocations("test-leak").count, 0);
+    }
+}
@@ -0,0 +1,350 @@
+//! Hardware interrupt (IRQ) handlers registry.
+//! Drivers claim an IRQ line through the `CPU` trait, either with a handler
+//! function, called in interrupt context, or with an `InterruptStream`, which
+//! wakes a task every time the line fires.
+//!
+//! Lines can be shared: every handler registered for a line is called until
+//! one of them reports the interrupt as its own, and every stream of the line
+//! is notified. The registry has a fixed
+//! size and handlers are plain functions, so dispatching never touches the heap.
+//! The registry lock is only taken with interrupts disabled, so an interrupt
+//! can't fire while the interrupted code holds it.
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use futures_util::stream::Stream;
+use futures_util::task::{AtomicWaker, Context, Poll};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Number of IRQ lines
+pub const IRQ_LINES: usize = 16;
+/// Maximum number of handlers sharing a line
+pub const MAX_HANDLERS_PER_LINE: usize = 4;
+
+/// Result of an interrupt handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The interrupt was raised by the handler's device
+    Handled,
+    /// The interrupt belongs to another device sharing the line
+    NotMine,
+}
+
+/// Interrupt handler, called in interrupt context (see `kernel::heap` for
+/// what it can't do) with the IRQ line.
+pub type IrqHandler = fn(u8) -> IrqReturn;
+
+/// Identifies a registration, used to unregister it.
+/// The generation tells registrations reusing the same slot apart, so a
+/// stale id can't unregister a newer handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    line: u8,
+    slot: u8,
+    generation: u32,
+}
+
+impl HandlerId {
+    pub fn line(&self) -> u8 {
+        self.line
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// There's no such IRQ line
+    InvalidLine(u8),
+    /// Every handler slot of the line is taken
+    LineFull(u8),
+    /// The handler isn't registered
+    NotRegistered,
+}
+
+#[derive(Clone, Copy)]
+enum Registration {
+    Handler(IrqHandler),
+    Stream,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    registration: Registration,
+    generation: u32,
+}
+
+/// State of an interrupt stream, kept by registration slot
+struct StreamEvents {
+    pending: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+impl StreamEvents {
+    const fn new() -> Self {
+        Self {
+            pending: AtomicUsize::new(0),
+            waker: AtomicWaker::new(),
+        }
+    }
+}
+
+const fn line_events() -> [StreamEvents; MAX_HANDLERS_PER_LINE] {
+    [
+        StreamEvents::new(),
+        StreamEvents::new(),
+        StreamEvents::new(),
+        StreamEvents::new(),
+    ]
+}
+
+static HANDLERS: Mutex<[[Option<Slot>; MAX_HANDLERS_PER_LINE]; IRQ_LINES]> =
+    Mutex::new([[None; MAX_HANDLERS_PER_LINE]; IRQ_LINES]);
+/// Generation of the next registration
+static NEXT_GENERATION: AtomicU32 = AtomicU32::new(0);
+
+// arrays of non Copy types can't be built with the repeat syntax
+static EVENTS: [[StreamEvents; MAX_HANDLERS_PER_LINE]; IRQ_LINES] = [
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+    line_events(),
+];
+
+/// Registers a handler for the given line.
+pub fn register_handler(line: u8, handler: IrqHandler) -> Result<HandlerId, IrqError> {
+    register(line, Registration::Handler(handler))
+}
+
+/// Registers a stream for the given line.
+pub fn register_stream(line: u8) -> Result<InterruptStream, IrqError> {
+    let id = register(line, Registration::Stream)?;
+    Ok(InterruptStream { id })
+}
+
+/// Removes a registration. Returns whether the line has no handler left.
+pub fn unregister(id: HandlerId) -> Result<bool, IrqError> {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = handlers
+            .get_mut(id.line as usize)
+            .ok_or(IrqError::InvalidLine(id.line))?;
+        let slot = id.slot as usize;
+        match line[slot] {
+            Some(registered) if registered.generation == id.generation => line[slot] = None,
+            _ => return Err(IrqError::NotRegistered),
+        }
+        EVENTS[id.line as usize][slot].waker.take();
+        Ok(line.iter().all(Option::is_none))
+    })
+}
+
+/// Whether the line has any handler registered
+pub fn is_registered(line: u8) -> bool {
+    without_interrupts(|| {
+        HANDLERS
+            .lock()
+            .get(line as usize)
+            .map_or(false, |handlers| handlers.iter().any(Option::is_some))
+    })
+}
+
+/// Calls the handlers of the given line, until one of them handles the
+/// interrupt, and notifies its streams. Returns `false` if no handler
+/// claimed it and the line has no stream.
+/// Must be called in interrupt context, with interrupts disabled.
+pub fn dispatch(line: u8) -> bool {
+    let handlers = match HANDLERS.lock().get(line as usize) {
+        Some(handlers) => *handlers,
+        None => return false,
+    };
+    let mut claimed = false;
+    let mut notified = false;
+    for (index, slot) in handlers.iter().enumerate() {
+        let registration = match slot {
+            Some(slot) => slot.registration,
+            None => continue,
+        };
+        match registration {
+            Registration::Handler(handler) => {
+                if !claimed {
+                    claimed = handler(line) == IrqReturn::Handled;
+                }
+            }
+            Registration::Stream => {
+                let events = &EVENTS[line as usize][index];
+                events.pending.fetch_add(1, Ordering::AcqRel);
+                events.waker.wake();
+                notified = true;
+            }
+        }
+    }
+    claimed || notified
+}
+
+fn register(line: u8, registration: Registration) -> Result<HandlerId, IrqError> {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let handlers = handlers
+            .get_mut(line as usize)
+            .ok_or(IrqError::InvalidLine(line))?;
+        let slot = handlers
+            .iter()
+            .position(Option::is_none)
+            .ok_or(IrqError::LineFull(line))?;
+        let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+        // events left by the previous registration of the slot
+        EVENTS[line as usize][slot]
+            .pending
+            .store(0, Ordering::Release);
+        handlers[slot] = Some(Slot {
+            registration,
+            generation,
+        });
+        Ok(HandlerId {
+            line,
+            slot: slot as u8,
+            generation,
+        })
+    })
+}
+
+/// Stream yielding, each time the line fires, the number of interrupts
+/// received since the previous item. It's meant for devices that don't need
+/// to be acknowledged in interrupt context; each stream on a line receives
+/// every interrupt. Dropping the stream doesn't unregister it: use
+/// `unregister` with its `id`.
+pub struct InterruptStream {
+    id: HandlerId,
+}
+
+impl InterruptStream {
+    pub fn id(&self) -> HandlerId {
+        self.id
+    }
+}
+
+impl Stream for InterruptStream {
+    type Item = usize;
+
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<usize>> {
+        let events = &EVENTS[self.id.line as usize][self.id.slot as usize];
+        events.waker.register(ctx.waker());
+        match events.pending.swap(0, Ordering::AcqRel) {
+            0 => Poll::Pending,
+            count => Poll::Ready(Some(count)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::task::{Context, Poll};
+
+    use futures_util::stream::StreamExt;
+    use futures_util::task::noop_waker_ref;
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    use super::{
+        dispatch, is_registered, register_handler, register_stream, unregister, IrqError,
+        IrqReturn, IRQ_LINES,
+    };
+
+    // a line not used by the kernel devices
+    const LINE: u8 = 10;
+
+    static FIRST_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static SECOND_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn first(_line: u8) -> IrqReturn {
+        FIRST_CALLS.fetch_add(1, Ordering::SeqCst);
+        IrqReturn::NotMine
+    }
+
+    fn second(_line: u8) -> IrqReturn {
+        SECOND_CALLS.fetch_add(1, Ordering::SeqCst);
+        IrqReturn::Handled
+    }
+
+    #[test_case]
+    fn test_shared_line_calls_every_handler() {
+        let first_id = register_handler(LINE, first).expect("Unable to register handler");
+        let second_id = register_handler(LINE, second).expect("Unable to register handler");
+
+        assert!(without_interrupts(|| dispatch(LINE)));
+        assert_eq!(FIRST_CALLS.load(Ordering::SeqCst), 1);
+        assert_eq!(SECOND_CALLS.load(Ordering::SeqCst), 1);
+
+        assert_eq!(unregister(second_id), Ok(false));
+        assert!(!without_interrupts(|| dispatch(LINE)));
+        assert_eq!(unregister(first_id), Ok(true));
+        assert_eq!(unregister(first_id), Err(IrqError::NotRegistered));
+    }
+
+    #[test_case]
+    fn test_stale_id_does_not_unregister_reused_slot() {
+        let stale = register_handler(LINE, first).expect("Unable to register handler");
+        assert_eq!(unregister(stale), Ok(true));
+        let id = register_handler(LINE, second).expect("Unable to register handler");
+        assert_eq!(id.line(), stale.line());
+
+        assert_eq!(unregister(stale), Err(IrqError::NotRegistered));
+        assert!(is_registered(LINE));
+        assert_eq!(unregister(id), Ok(true));
+    }
+
+    fn claim(_line: u8) -> IrqReturn {
+        IrqReturn::Handled
+    }
+
+    #[test_case]
+    fn test_every_stream_of_a_line_is_notified() {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let handler = register_handler(LINE, claim).expect("Unable to register handler");
+        let mut first_stream = register_stream(LINE).expect("Unable to register stream");
+        let mut second_stream = register_stream(LINE).expect("Unable to register stream");
+        assert_eq!(first_stream.poll_next_unpin(&mut ctx), Poll::Pending);
+        assert_eq!(second_stream.poll_next_unpin(&mut ctx), Poll::Pending);
+
+        // the handler claims the interrupts, the streams still get them
+        assert!(without_interrupts(|| dispatch(LINE)));
+        assert!(without_interrupts(|| dispatch(LINE)));
+        assert_eq!(first_stream.poll_next_unpin(&mut ctx), Poll::Ready(Some(2)));
+        assert_eq!(
+            second_stream.poll_next_unpin(&mut ctx),
+            Poll::Ready(Some(2))
+        );
+        assert_eq!(first_stream.poll_next_unpin(&mut ctx), Poll::Pending);
+
+        assert!(without_interrupts(|| dispatch(LINE)));
+        assert_eq!(
+            second_stream.poll_next_unpin(&mut ctx),
+            Poll::Ready(Some(1))
+        );
+        assert_eq!(first_stream.poll_next_unpin(&mut ctx), Poll::Ready(Some(1)));
+
+        assert_eq!(unregister(handler), Ok(false));
+        assert_eq!(unregister(first_stream.id()), Ok(false));
+        assert_eq!(unregister(second_stream.id()), Ok(true));
+    }
+
+    #[test_case]
+    fn test_invalid_line_is_rejected() {
+        assert_eq!(
+            register_handler(IRQ_LINES as u8, first),
+            Err(IrqError::InvalidLine(IRQ_LINES as u8))
+        );
+    }
+}
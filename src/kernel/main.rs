@@ -1,36 +1,36 @@
 use bootloader::bootinfo::MemoryMap;
 use futures_util::stream::StreamExt;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-use x86_64::structures::paging::PageTableFlags;
-use x86_64::VirtAddr;
 
 use crate::hal::arch::x86_64::cpu::X86CPU;
-use crate::hal::arch::x86_64::memory::Memory;
+use crate::hal::arch::x86_64::memory::{init_memory, memory_map};
+use crate::hal::arch::x86_64::protection::harden_kernel;
+use crate::kernel::boot_interrupt_mode;
 use crate::kernel::cpu::{CPUEvents, CPU};
 use crate::kernel::event_loop::executor::EventLoopExecutor;
-use crate::kernel::{HEAP_SIZE, HEAP_START_ADDRESS};
+use crate::kernel::exceptions::kernel_exception_handler;
+use crate::kernel::heap::init_heap;
 use crate::kprint;
 
 // TODO list
 // - [ ] Receive a struct as kernel_main parameter
 // - [ ] Change CPU keyboard_stream to cpu_events_stream
 // - [ ] Kernel OnceCell struct (with processor, mem... events are handled by the kernel)
-// - [ ] Add support for timer interruption
-// - [ ] Add support for exception interruptions
+// - [x] Add support for timer interruption
+// - [x] Add support for exception interruptions
 
 pub fn kernel_main(memory_offset: u64, mem_map: &'static MemoryMap) -> ! {
-    let mut mem = Memory::new(memory_offset, mem_map);
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-    mem.alloc_frames(VirtAddr::new(HEAP_START_ADDRESS as u64), HEAP_SIZE, flags)
-        .expect("Unable to allocate frames for Kernel heap");
+    let mem = init_memory(memory_offset, mem_map);
+    memory_map().print();
+    init_heap();
+    harden_kernel(&mut mem.lock()).expect("Unable to enforce W^X on kernel mappings");
 
-    // TODO to be removed
-    mem.print_l4_table();
-
-    let processor = X86CPU::new();
+    let processor = X86CPU::with_interrupt_mode(boot_interrupt_mode());
     let mut event_loop = EventLoopExecutor::new();
+    processor.set_exception_handler(kernel_exception_handler);
+    processor.init();
 
-    // keyboard handler
+    // keyboard handler, once the interrupt controller can enable its line
     let mut keyboard_stream = processor.get_keyboard_stream();
     event_loop.wrap_future(async move {
         let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
@@ -47,8 +47,7 @@ pub fn kernel_main(memory_offset: u64, mem_map: &'static MemoryMap) -> ! {
         }
     });
 
-    processor.init();
-    event_loop.run(|| processor.hlt());
+    event_loop.run(&processor);
 
     // TODO shutdown processor
     loop {}
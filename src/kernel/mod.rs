@@ -1,14 +1,50 @@
+use crate::hal::arch::x86_64::fw_cfg::read_file;
+use crate::hal::arch::x86_64::interrupt_controller::InterruptMode;
+use crate::kprintln;
+
 pub mod console;
 pub mod cpu;
 pub mod cpu_events;
 mod event_loop;
+pub mod exceptions;
 pub mod heap;
+pub mod heap_tracker;
+pub mod irq;
 pub mod main;
+pub mod slab;
+pub mod time;
+pub mod timer;
+
+/// Boot option (QEMU fw_cfg file) selecting the interrupt controller:
+/// `apic` or `pic`
+pub const INTERRUPT_MODE_OPTION: &str = "opt/hendrix/interrupts";
+
+/// Interrupt controller selected at boot by `INTERRUPT_MODE_OPTION`.
+/// Defaults to the APIC, which falls back to the 8259 PIC if it isn't available.
+pub fn boot_interrupt_mode() -> InterruptMode {
+    let mut name = [0; 8];
+    let len = match read_file(INTERRUPT_MODE_OPTION, &mut name) {
+        Some(len) => len,
+        None => return InterruptMode::Apic,
+    };
+    InterruptMode::from_name(&name[..len]).unwrap_or_else(|| {
+        kprintln!("Unknown interrupt mode, using the APIC");
+        InterruptMode::Apic
+    })
+}
+
+/// Frequency of the timer interrupt, in Hz
+pub const TIMER_FREQUENCY: u32 = 1000;
 
 /// Virtual address of the beginning of the Kernel heap
 pub const HEAP_START_ADDRESS: usize = 0x_4444_4444_0000;
-/// Heap size in Bytes
+/// Initial heap size in Bytes, mapped when the kernel starts
 pub const HEAP_SIZE: usize = 1 * 1024 * 1024; // 1 MiB
+/// Size of the virtual window reserved for the heap, which is the
+/// maximum size it can grow to
+pub const HEAP_MAX_SIZE: usize = 1 * 1024 * 1024 * 1024; // 1 GiB
+/// Minimum amount of memory mapped each time the heap grows
+pub const HEAP_GROWTH_SIZE: usize = 1 * 1024 * 1024; // 1 MiB
 /// The buddy allocator allocate memory in blocks
 /// This constant defines the block's size
 pub const HEAP_LEAF_SIZE: usize = 16;
@@ -0,0 +1,172 @@
+//! Slab allocator used as front-end of the kernel heap for small objects.
+//! Objects are grouped in caches by size class; each cache carves its
+//! objects from slabs (`SLAB_SIZE` blocks requested to the buddy allocator)
+//! and keeps the free objects in an intrusive linked list, so allocating and
+//! freeing a small object doesn't touch the buddy allocator at all.
+//!
+//! Slabs are never returned to the buddy allocator: once a cache grows, its
+//! free objects are kept around to be reused by objects of the same size class.
+
+/// Size of the blocks the caches request to the buddy allocator
+pub const SLAB_SIZE: usize = 4096;
+
+/// Number of slab caches
+pub const SLAB_CACHES: usize = 8;
+
+/// Object sizes handled by the slab caches. Larger objects go straight to
+/// the buddy allocator.
+const SIZE_CLASSES: [usize; SLAB_CACHES] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Header written in each free object, linking it to the next free one.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Usage statistics of a slab cache
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    /// Size of the objects handled by the cache
+    pub object_size: usize,
+    /// Number of slabs owned by the cache
+    pub slabs: usize,
+    /// Number of objects currently allocated
+    pub objects_in_use: usize,
+    /// Number of objects available without requesting a new slab
+    pub free_objects: usize,
+    /// Total number of allocations served by the cache
+    pub allocations: usize,
+    /// Total number of frees handled by the cache
+    pub frees: usize,
+}
+
+/// Cache of objects with the same size
+struct SlabCache {
+    object_size: usize,
+    free_list: *mut FreeObject,
+    stats: SlabStats,
+}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            free_list: core::ptr::null_mut(),
+            stats: SlabStats {
+                object_size,
+                slabs: 0,
+                objects_in_use: 0,
+                free_objects: 0,
+                allocations: 0,
+                frees: 0,
+            },
+        }
+    }
+
+    /// Splits the given slab in objects and adds them to the free list.
+    /// Unsafe because `slab` must point to `SLAB_SIZE` bytes of unused memory.
+    unsafe fn add_slab(&mut self, slab: *mut u8) {
+        let objects = SLAB_SIZE / self.object_size;
+        for index in (0..objects).rev() {
+            self.push(slab.add(index * self.object_size));
+        }
+        self.stats.slabs += 1;
+    }
+
+    fn alloc(&mut self) -> *mut u8 {
+        let object = self.free_list;
+        if object.is_null() {
+            return core::ptr::null_mut();
+        }
+        unsafe {
+            self.free_list = (*object).next;
+        }
+        self.stats.free_objects -= 1;
+        self.stats.objects_in_use += 1;
+        self.stats.allocations += 1;
+        object as *mut u8
+    }
+
+    /// Unsafe because `ptr` must be an object allocated from this cache.
+    unsafe fn free(&mut self, ptr: *mut u8) {
+        self.push(ptr);
+        self.stats.objects_in_use -= 1;
+        self.stats.frees += 1;
+    }
+
+    unsafe fn push(&mut self, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        (*object).next = self.free_list;
+        self.free_list = object;
+        self.stats.free_objects += 1;
+    }
+}
+
+/// Set of slab caches, one per size class.
+pub struct SlabAllocator {
+    caches: [SlabCache; SLAB_CACHES],
+    /// Alignment guaranteed for the slabs, and therefore the maximum
+    /// alignment the caches can serve
+    slab_align: usize,
+}
+
+impl SlabAllocator {
+    pub const fn new(slab_align: usize) -> Self {
+        Self {
+            caches: [
+                SlabCache::new(SIZE_CLASSES[0]),
+                SlabCache::new(SIZE_CLASSES[1]),
+                SlabCache::new(SIZE_CLASSES[2]),
+                SlabCache::new(SIZE_CLASSES[3]),
+                SlabCache::new(SIZE_CLASSES[4]),
+                SlabCache::new(SIZE_CLASSES[5]),
+                SlabCache::new(SIZE_CLASSES[6]),
+                SlabCache::new(SIZE_CLASSES[7]),
+            ],
+            slab_align,
+        }
+    }
+
+    /// Returns the index of the cache able to serve objects with the given
+    /// size and alignment, or `None` if it must be served by the buddy allocator.
+    pub fn cache_index(&self, size: usize, align: usize) -> Option<usize> {
+        if align > self.slab_align {
+            return None;
+        }
+        SIZE_CLASSES
+            .iter()
+            .position(|&class| class >= size && class % align == 0)
+    }
+
+    /// Whether the given cache has no free object and needs a new slab
+    pub fn needs_slab(&self, index: usize) -> bool {
+        self.caches[index].free_list.is_null()
+    }
+
+    /// Gives a new slab to the given cache.
+    /// Unsafe because `slab` must point to `SLAB_SIZE` bytes of unused
+    /// memory, aligned to the `slab_align` given when creating the allocator.
+    pub unsafe fn add_slab(&mut self, index: usize, slab: *mut u8) {
+        self.caches[index].add_slab(slab)
+    }
+
+    /// Allocates an object from the given cache, returning a null pointer
+    /// if the cache needs a new slab.
+    pub fn alloc(&mut self, index: usize) -> *mut u8 {
+        self.caches[index].alloc()
+    }
+
+    /// Returns an object to the given cache.
+    /// Unsafe because `ptr` must have been allocated from the same cache.
+    pub unsafe fn free(&mut self, index: usize, ptr: *mut u8) {
+        self.caches[index].free(ptr)
+    }
+
+    /// Returns the usage statistics of every cache
+    pub fn stats(&self) -> [SlabStats; SLAB_CACHES] {
+        let mut stats = [self.caches[0].stats; SLAB_CACHES];
+        for (stat, cache) in stats.iter_mut().zip(self.caches.iter()) {
+            *stat = cache.stats;
+        }
+        stats
+    }
+}
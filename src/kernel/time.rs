@@ -0,0 +1,186 @@
+//! Kernel monotonic clock.
+//! The timer interrupt counts ticks of a known period, which gives the uptime
+//! with the timer resolution. When the TSC frequency is known the clock reads
+//! the TSC instead, for a nanosecond resolution.
+//!
+//! The clock starts when `init` is called by the CPU, before the timer
+//! interrupt is enabled; until then it stays at zero.
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::hal::arch::x86_64::pit::read_tsc;
+
+use super::timer;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Number of timer interrupts since the clock started
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Timer period, in nanoseconds
+static TICK_PERIOD: AtomicU64 = AtomicU64::new(0);
+/// TSC frequency in Hz, 0 if unknown
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// TSC value when the clock started
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the clock, given the period of the timer interrupt and the TSC
+/// frequency (if it could be measured).
+pub fn init(tick_period: Duration, tsc_frequency: Option<u64>) {
+    TICK_PERIOD.store(tick_period.as_nanos() as u64, Ordering::Relaxed);
+    TSC_FREQUENCY.store(tsc_frequency.unwrap_or(0), Ordering::Relaxed);
+    TSC_START.store(read_tsc(), Ordering::Relaxed);
+    TICKS.store(0, Ordering::Release);
+}
+
+/// Timer period of the tests
+/// Called by the timer interrupt handler
+pub fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::AcqRel) + 1;
+    timer::expire_timers(now);
+}
+
+/// Number of timer interrupts since the clock started
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Acquire)
+}
+
+/// Period of the timer interrupt, which is the resolution of `ticks`
+pub fn tick_period() -> Duration {
+    Duration::from_nanos(TICK_PERIOD.load(Ordering::Relaxed))
+}
+
+/// Time elapsed since the clock started
+pub fn uptime() -> Duration {
+    Instant::now().duration_since(Instant::START)
+}
+
+/// A point of the monotonic clock, with a nanosecond precision
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    /// Nanoseconds since the clock started
+    nanos: u64,
+}
+
+impl Instant {
+    /// When the clock started
+    pub const START: Instant = Instant { nanos: 0 };
+
+    pub fn now() -> Self {
+        let tsc_frequency = TSC_FREQUENCY.load(Ordering::Relaxed) as u128;
+        let nanos = if tsc_frequency != 0 {
+            let cycles = read_tsc().wrapping_sub(TSC_START.load(Ordering::Relaxed)) as u128;
+            cycles * NANOS_PER_SEC / tsc_frequency
+        } else {
+            ticks() as u128 * TICK_PERIOD.load(Ordering::Relaxed) as u128
+        };
+        Self {
+            nanos: nanos as u64,
+        }
+    }
+
+    /// Time elapsed from `earlier` to this instant, or zero if `earlier`
+    /// is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = self.nanos.checked_add(duration_nanos(duration)?)?;
+        Some(Instant { nanos })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = self.nanos.checked_sub(duration_nanos(duration)?)?;
+        Some(Instant { nanos })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, other: Instant) -> Duration {
+        self.duration_since(other)
+    }
+}
+
+fn duration_nanos(duration: Duration) -> Option<u64> {
+    let nanos = duration.as_nanos();
+    if nanos > u64::MAX as u128 {
+        None
+    } else {
+        Some(nanos as u64)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use core::time::Duration;
+
+    use super::{init, tick, ticks, uptime, Instant};
+    use crate::hal::arch::x86_64::pit::calibrate_tsc;
+
+    pub(crate) const TEST_TICK_PERIOD: Duration = Duration::from_millis(1);
+
+    /// Starts the clock once for the tests, which run without the timer
+    /// interrupt: they call `tick` themselves, for a `TEST_TICK_PERIOD` period.
+    pub(crate) fn init_for_tests() {
+        static STARTED: AtomicBool = AtomicBool::new(false);
+        if !STARTED.swap(true, Ordering::AcqRel) {
+            init(TEST_TICK_PERIOD, calibrate_tsc());
+        }
+    }
+
+    #[test_case]
+    fn test_instant_arithmetic() {
+        let later = Instant::START + Duration::from_millis(1500);
+        assert_eq!(later - Instant::START, Duration::from_millis(1500));
+        assert_eq!(Instant::START.duration_since(later), Duration::from_secs(0));
+        assert_eq!(
+            later.checked_sub(Duration::from_millis(500)),
+            Some(Instant::START + Duration::from_secs(1))
+        );
+        assert_eq!(Instant::START.checked_sub(Duration::from_nanos(1)), None);
+        assert_eq!(later.checked_add(Duration::from_secs(u64::MAX)), None);
+    }
+
+    #[test_case]
+    fn test_clock_is_monotonic() {
+        init_for_tests();
+        let first = Instant::now();
+        let ticks_before = ticks();
+        tick();
+        assert_eq!(ticks(), ticks_before + 1);
+
+        // the TSC clock progresses by itself, the ticks clock did with `tick`
+        let mut now = Instant::now();
+        for _ in 0..1_000_000 {
+            if now > first {
+                break;
+            }
+            now = Instant::now();
+        }
+        assert!(now > first);
+        assert!(uptime() >= now.duration_since(Instant::START));
+    }
+}
@@ -0,0 +1,425 @@
+//! Timers for async tasks: `sleep`, `interval` and `timeout`.
+//! Pending timers are kept in a hashed timer wheel, indexed by the tick they
+//! expire at; the timer interrupt walks the slot of each new tick and wakes
+//! the tasks waiting on it. Timers have the resolution of the timer interrupt
+//! (see `time::tick_period`).
+//!
+//! Timers need the clock: creating one before `time::init` panics, as it
+//! would never expire.
+//!
+//! The interrupt only wakes tasks, by reference: timers are added and removed
+//! by their futures, in task context, so the interrupt never touches the heap.
+//! The wheel lock is only taken with interrupts disabled outside of the timer
+//! interrupt.
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::time;
+
+/// Number of slots of the wheel; timers further than that many ticks
+/// share the slots and are skipped until their round comes.
+const WHEEL_SLOTS: usize = 256;
+
+static TIMERS: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+
+/// Identifies a timer of the wheel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TimerId {
+    id: u64,
+    deadline: u64,
+}
+
+struct Timer {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+}
+
+struct TimerWheel {
+    /// Pending timers, by `deadline % WHEEL_SLOTS`; allocated with the
+    /// first timer
+    slots: Vec<Vec<Timer>>,
+    /// Last tick processed
+    current: u64,
+    next_id: u64,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            current: 0,
+            next_id: 0,
+        }
+    }
+
+    /// Checks whether the deadline has passed, adding a timer to wake the
+    /// task otherwise, or updating its waker. The timer is removed once
+    /// the deadline is reached.
+    fn poll(&mut self, timer: &mut Option<TimerId>, deadline: u64, waker: &Waker) -> Poll<()> {
+        if self.current >= deadline {
+            if let Some(id) = timer.take() {
+                self.remove(id);
+            }
+            return Poll::Ready(());
+        }
+        match timer {
+            Some(id) => {
+                let slot = &mut self.slots[slot_of(id.deadline)];
+                if let Some(timer) = slot.iter_mut().find(|timer| timer.id == id.id) {
+                    if !timer.waker.will_wake(waker) {
+                        timer.waker = waker.clone();
+                    }
+                }
+            }
+            None => *timer = Some(self.insert(deadline, waker.clone())),
+        }
+        Poll::Pending
+    }
+
+    fn insert(&mut self, deadline: u64, waker: Waker) -> TimerId {
+        if self.slots.is_empty() {
+            self.slots.resize_with(WHEEL_SLOTS, Vec::new);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.slots[slot_of(deadline)].push(Timer {
+            id,
+            deadline,
+            waker,
+        });
+        TimerId { id, deadline }
+    }
+
+    fn remove(&mut self, id: TimerId) {
+        let slot = &mut self.slots[slot_of(id.deadline)];
+        if let Some(index) = slot.iter().position(|timer| timer.id == id.id) {
+            slot.swap_remove(index);
+        }
+    }
+
+    /// Processes every tick up to `now`, waking the expired timers
+    fn expire(&mut self, now: u64) {
+        while self.current < now {
+            self.current += 1;
+            let current = self.current;
+            if let Some(slot) = self.slots.get(slot_of(current)) {
+                for timer in slot.iter().filter(|timer| timer.deadline == current) {
+                    timer.waker.wake_by_ref();
+                }
+            }
+        }
+    }
+}
+
+fn slot_of(deadline: u64) -> usize {
+    deadline as usize % WHEEL_SLOTS
+}
+
+/// Called by the timer interrupt, with the current tick
+pub(super) fn expire_timers(now: u64) {
+    TIMERS.lock().expire(now);
+}
+
+/// Number of ticks in the duration, rounded up.
+/// Panics if the clock isn't running.
+fn ticks_of(duration: Duration) -> u64 {
+    let period = time::tick_period().as_nanos();
+    assert!(period != 0, "Timer created before the clock started");
+    ((duration.as_nanos() + period - 1) / period) as u64
+}
+
+/// Tick at which a timer started now for `duration` expires, so that it
+/// lasts at least `duration`.
+fn deadline_after(duration: Duration) -> u64 {
+    match ticks_of(duration) {
+        0 => time::ticks(),
+        // the current tick is already partially elapsed
+        ticks => time::ticks().saturating_add(ticks + 1),
+    }
+}
+
+fn poll_deadline(timer: &mut Option<TimerId>, deadline: u64, ctx: &mut Context) -> Poll<()> {
+    without_interrupts(|| TIMERS.lock().poll(timer, deadline, ctx.waker()))
+}
+
+fn cancel(timer: &mut Option<TimerId>) {
+    if let Some(id) = timer.take() {
+        without_interrupts(|| TIMERS.lock().remove(id));
+    }
+}
+
+/// Completes after the given duration.
+/// Panics if the clock isn't running.
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: deadline_after(duration),
+        timer: None,
+    }
+}
+
+/// Future returned by `sleep`
+pub struct Sleep {
+    deadline: u64,
+    timer: Option<TimerId>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        let deadline = self.deadline;
+        poll_deadline(&mut self.timer, deadline, ctx)
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        cancel(&mut self.timer);
+    }
+}
+
+/// Stream yielding once every `period`, starting one period from now.
+/// Periods missed because the task didn't poll the stream in time are
+/// skipped.
+/// Panics if the clock isn't running.
+pub fn interval(period: Duration) -> Interval {
+    Interval {
+        period: ticks_of(period).max(1),
+        deadline: deadline_after(period),
+        timer: None,
+    }
+}
+
+/// Stream returned by `interval`
+pub struct Interval {
+    /// Period, in ticks
+    period: u64,
+    deadline: u64,
+    timer: Option<TimerId>,
+}
+
+impl Stream for Interval {
+    type Item = ();
+
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<()>> {
+        let deadline = self.deadline;
+        match poll_deadline(&mut self.timer, deadline, ctx) {
+            Poll::Ready(()) => {
+                let now = time::ticks();
+                self.deadline = deadline + self.period;
+                if self.deadline <= now {
+                    self.deadline = now + self.period;
+                }
+                Poll::Ready(Some(()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Interval {
+    fn drop(&mut self) {
+        cancel(&mut self.timer);
+    }
+}
+
+/// Error returned by `timeout` when the duration elapsed first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Runs the future until it completes or the duration elapses, whichever
+/// comes first, returning `Elapsed` in the latter case.
+/// Panics if the clock isn't running.
+pub fn timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+/// Future returned by `timeout`
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        // the future is never moved out of the pinned timeout
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(ctx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(ctx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use alloc::task::Wake;
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::task::{Context, Poll, Waker};
+    use core::time::Duration;
+
+    use futures_util::future::{pending, ready};
+    use futures_util::stream::StreamExt;
+
+    use super::{
+        interval, sleep, slot_of, timeout, Elapsed, TimerId, TimerWheel, TIMERS, WHEEL_SLOTS,
+    };
+    use crate::kernel::time;
+    use crate::kernel::time::tests::{init_for_tests, TEST_TICK_PERIOD};
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test_case]
+    fn test_timer_is_woken_at_deadline() {
+        let wakes = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(wakes.clone());
+        let mut wheel = TimerWheel::new();
+        let mut timer = None;
+
+        assert_eq!(wheel.poll(&mut timer, 3, &waker), Poll::Pending);
+        wheel.expire(2);
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+        wheel.expire(3);
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+
+        assert_eq!(wheel.poll(&mut timer, 3, &waker), Poll::Ready(()));
+        assert!(timer.is_none());
+        assert!(wheel.slots.iter().all(|slot| slot.is_empty()));
+    }
+
+    #[test_case]
+    fn test_timer_waits_for_its_round() {
+        let wakes = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(wakes.clone());
+        let mut wheel = TimerWheel::new();
+        let mut timer = None;
+
+        let deadline = WHEEL_SLOTS as u64 + 1;
+        assert_eq!(wheel.poll(&mut timer, deadline, &waker), Poll::Pending);
+        wheel.expire(1);
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+        wheel.expire(deadline);
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+    }
+
+    fn is_in_wheel(id: TimerId) -> bool {
+        TIMERS.lock().slots[slot_of(id.deadline)]
+            .iter()
+            .any(|timer| timer.id == id.id)
+    }
+
+    #[test_case]
+    fn test_sleep_lasts_at_least_its_duration() {
+        init_for_tests();
+        let wakes = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(wakes.clone());
+        let mut ctx = Context::from_waker(&waker);
+
+        // 1.5 periods round up to 2, plus the current partial tick
+        let mut sleeping = sleep(TEST_TICK_PERIOD * 3 / 2);
+        assert_eq!(Pin::new(&mut sleeping).poll(&mut ctx), Poll::Pending);
+        time::tick();
+        time::tick();
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+        assert_eq!(Pin::new(&mut sleeping).poll(&mut ctx), Poll::Pending);
+        time::tick();
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        assert_eq!(Pin::new(&mut sleeping).poll(&mut ctx), Poll::Ready(()));
+
+        let mut sleeping = sleep(Duration::from_secs(0));
+        assert_eq!(Pin::new(&mut sleeping).poll(&mut ctx), Poll::Ready(()));
+    }
+
+    #[test_case]
+    fn test_interval_skips_missed_periods() {
+        init_for_tests();
+        let wakes = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(wakes.clone());
+        let mut ctx = Context::from_waker(&waker);
+
+        let mut ticker = interval(TEST_TICK_PERIOD);
+        assert_eq!(ticker.poll_next_unpin(&mut ctx), Poll::Pending);
+        time::tick();
+        time::tick();
+        assert_eq!(ticker.poll_next_unpin(&mut ctx), Poll::Ready(Some(())));
+
+        // five periods go by without polling: they yield a single item
+        for _ in 0..5 {
+            time::tick();
+        }
+        assert_eq!(ticker.poll_next_unpin(&mut ctx), Poll::Ready(Some(())));
+        assert_eq!(ticker.poll_next_unpin(&mut ctx), Poll::Pending);
+        time::tick();
+        assert_eq!(ticker.poll_next_unpin(&mut ctx), Poll::Ready(Some(())));
+    }
+
+    #[test_case]
+    fn test_timeout() {
+        init_for_tests();
+        let wakes = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(wakes.clone());
+        let mut ctx = Context::from_waker(&waker);
+
+        let mut completed = timeout(ready(42), TEST_TICK_PERIOD);
+        assert_eq!(Pin::new(&mut completed).poll(&mut ctx), Poll::Ready(Ok(42)));
+
+        let mut expired = timeout(pending::<()>(), TEST_TICK_PERIOD);
+        assert_eq!(Pin::new(&mut expired).poll(&mut ctx), Poll::Pending);
+        time::tick();
+        time::tick();
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            Pin::new(&mut expired).poll(&mut ctx),
+            Poll::Ready(Err(Elapsed))
+        );
+    }
+
+    #[test_case]
+    fn test_dropping_sleep_cancels_its_timer() {
+        init_for_tests();
+        let wakes = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(wakes.clone());
+        let mut ctx = Context::from_waker(&waker);
+
+        let mut sleeping = sleep(TEST_TICK_PERIOD);
+        assert_eq!(Pin::new(&mut sleeping).poll(&mut ctx), Poll::Pending);
+        let id = sleeping.timer.expect("The sleep has no timer");
+        assert!(is_in_wheel(id));
+
+        drop(sleeping);
+        assert!(!is_in_wheel(id));
+        time::tick();
+        time::tick();
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+    }
+}
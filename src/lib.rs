@@ -1,5 +1,7 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
+#![feature(asm)]
+#![feature(global_asm)]
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
 #![feature(alloc_error_handler)]
@@ -32,17 +34,23 @@ entry_point!(kernel_test_main);
 #[cfg(test)]
 fn kernel_test_main(boot_info: &'static BootInfo) -> ! {
     use {
-        crate::hal::arch::x86_64::memory::Memory,
-        crate::kernel::{HEAP_SIZE, HEAP_START_ADDRESS},
-        x86_64::structures::paging::PageTableFlags,
-        x86_64::VirtAddr,
+        crate::hal::arch::x86_64::cpu::init_exceptions,
+        crate::hal::arch::x86_64::interrupt_controller::{
+            init_interrupt_controller, InterruptMode,
+        },
+        crate::hal::arch::x86_64::memory::init_memory,
+        crate::hal::arch::x86_64::protection::harden_kernel,
+        crate::kernel::heap::init_heap,
     };
 
     // To run the test it's required to have memory setup
-    let mut mem = Memory::new(boot_info.physical_memory_offset, &boot_info.memory_map);
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-    mem.alloc_frames(VirtAddr::new(HEAP_START_ADDRESS as u64), HEAP_SIZE, flags)
-        .expect("Unable to allocate virtual memory");
+    let mem = init_memory(boot_info.physical_memory_offset, &boot_info.memory_map);
+    init_heap();
+    harden_kernel(&mut mem.lock()).expect("Unable to enforce W^X on kernel mappings");
+    // tests may fault on purpose, e.g. touching lazily mapped memory
+    init_exceptions();
+    // interrupts stay disabled and every line masked until a test enables them
+    init_interrupt_controller(InterruptMode::Pic);
 
     test_main();
 
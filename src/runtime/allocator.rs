@@ -11,6 +11,11 @@ static ALLOCATOR: Locked<HeapAllocator> = Locked::new(HeapAllocator::new(
     HEAP_LEAF_SIZE,
 ));
 
+/// Returns the kernel heap used as the Rust global allocator
+pub fn kernel_heap() -> &'static Locked<HeapAllocator> {
+    &ALLOCATOR
+}
+
 // Error handler for memory allocation errors
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
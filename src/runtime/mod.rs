@@ -1,2 +1,2 @@
-mod allocator;
+pub mod allocator;
 pub mod testing;